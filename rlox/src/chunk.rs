@@ -1,28 +1,67 @@
 use std::fmt::{Display, Error, Formatter};
+use std::mem;
+use std::rc::Rc;
 
 // Not including types you intend to use
 // can cause great trouble if the names
 // conflict with anything from the prelude.
 use crate::list::List;
 
-use crate::common::OpCode;
+use crate::common::{OpCode, Span};
+use crate::diagnostic::{self, Source};
+
+// the most literals a ConstantLong's three byte operand can address
+const LITERALS_MAX: usize = 1 << 24;
+
+// What the disassembler needs to know about a constant beyond how to print it
+pub trait Disassemble: Display {
+    // how many (is_local, index) operand pairs follow a closure over this constant
+    fn upvalue_count(&self) -> usize;
+}
 
 pub struct Chunk<V: Display> {
     name: String,
     bytecode: List<u8>,
+    // names and functions, which instructions address with a single byte
     constants: List<V>,
-    // TODO: change this to use run length encoding instead of storing the line for every
-    // single byte
-    lines: List<usize>,
+    // Values of literals in the code. They are kept apart so that however
+    // many a chunk holds, the one byte operands still reach every name.
+    literals: List<V>,
+    // one run per stretch of bytecode compiled from the same line, in offset order
+    lines: List<LineRun>,
+    // likewise per stretch compiled from the same token, for error messages
+    spans: List<SpanRun>,
+    // the code the spans point into
+    source: Rc<Source>,
+}
+
+// Consecutive bytes share a line far more often than not, so lines are stored
+// once per run instead of once per byte
+struct LineRun {
+    // offset of the first byte in the run
+    start: usize,
+    line: usize,
+}
+
+// Only the byte range of the token is kept. Its line and column are worked
+// out from the source when an error actually needs them.
+struct SpanRun {
+    // offset of the first byte in the run
+    start: usize,
+    source_start: u32,
+    source_end: u32,
 }
 
 impl<V: Display> Chunk<V> {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, source: Rc<Source>) -> Self {
         Chunk {
             name,
             bytecode: List::new(),
             constants: List::new(),
+            literals: List::new(),
             lines: List::new(),
+            spans: List::new(),
+            source,
         }
     }
 
@@ -30,31 +69,123 @@ impl<V: Display> Chunk<V> {
         self.bytecode[offset]
     }
 
-    pub fn write_byte(&mut self, byte: u8, line: usize) {
+    pub fn write_byte(&mut self, byte: u8, span: Span) {
+        if self.lines.last().is_none_or(|run| run.line != span.line) {
+            self.lines.push(LineRun {
+                start: self.bytecode.len(),
+                line: span.line,
+            });
+        }
+        // lox sources are nowhere near 4GiB, so the offsets fit in a u32
+        let (source_start, source_end) = (span.start as u32, span.end as u32);
+        if self
+            .spans
+            .last()
+            .is_none_or(|run| (run.source_start, run.source_end) != (source_start, source_end))
+        {
+            self.spans.push(SpanRun {
+                start: self.bytecode.len(),
+                source_start,
+                source_end,
+            });
+        }
         self.bytecode.push(byte);
-        self.lines.push(line);
+    }
+
+    // Overwrites an already written byte, used to backpatch jump operands
+    pub fn set_byte(&mut self, offset: usize, byte: u8) {
+        self.bytecode[offset] = byte;
+    }
+
+    pub fn bytecode_len(&self) -> usize {
+        self.bytecode.len()
+    }
+
+    // The source line the byte at the offset was compiled from
+    pub fn line_at(&self, offset: usize) -> usize {
+        // the run the offset falls in is the last one starting at or before it
+        let run = self.lines.partition_point(|run| run.start <= offset);
+        self.lines[run - 1].line
+    }
+
+    // The token the byte at the offset was compiled from. Slower than
+    // line_at as it scans the source, so only meant for reporting errors.
+    pub fn span_at(&self, offset: usize) -> Span {
+        let run = self.spans.partition_point(|run| run.start <= offset);
+        let run = &self.spans[run - 1];
+        diagnostic::locate(
+            &self.source,
+            run.source_start as usize,
+            run.source_end as usize,
+        )
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
     }
 
     pub fn get_constant(&self, lookup: usize) -> &V {
         &self.constants[lookup]
     }
 
-    pub fn update_constant(&mut self, lookup: usize, replacement: V) {
-        self.constants[lookup] = replacement;
+    pub fn get_literal(&self, lookup: usize) -> &V {
+        &self.literals[lookup]
     }
 
     pub fn add_constant(&mut self, value: V) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
+
+    // Adds a literal along with the instruction that loads it, which takes a
+    // one byte operand while the index fits and a three byte one after that.
+    // Returns None once not even three bytes can address the literal.
+    pub fn write_constant(&mut self, value: V, span: Span) -> Option<usize> {
+        let lookup = self.literals.len();
+        if lookup >= LITERALS_MAX {
+            return None;
+        }
+        self.literals.push(value);
+
+        if let Ok(byte) = u8::try_from(lookup) {
+            self.write_byte(OpCode::Constant as u8, span);
+            self.write_byte(byte, span);
+        } else {
+            self.write_byte(OpCode::ConstantLong as u8, span);
+            // big endian, like jump operands
+            self.write_byte((lookup >> 16) as u8, span);
+            self.write_byte((lookup >> 8) as u8, span);
+            self.write_byte(lookup as u8, span);
+        }
+        Some(lookup)
+    }
+
+    // every value the chunk refers to, names and literals alike
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.constants.iter().chain(self.literals.iter())
+    }
+
+    pub fn constants(&self) -> &[V] {
+        &self.constants
+    }
+
+    // bytes held by the chunk's buffers
+    pub fn size(&self) -> usize {
+        self.bytecode.len()
+            + (self.constants.len() + self.literals.len()) * mem::size_of::<V>()
+            + self.lines.len() * mem::size_of::<LineRun>()
+            + self.spans.len() * mem::size_of::<SpanRun>()
+    }
 }
-impl<V: Display> Display for Chunk<V> {
+impl<V: Disassemble> Display for Chunk<V> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         let Chunk {
             name,
             bytecode,
             constants,
+            literals,
             lines,
+            spans,
             ..
         } = self;
 
@@ -66,21 +197,83 @@ impl<V: Display> Display for Chunk<V> {
             offset += match OpCode::try_from(bytecode[offset]) {
                 Ok(opcode) => {
                     write!(f, "{:04} ", offset)?;
-                    if offset > 0 && lines[offset] == lines[offset - 1] {
+                    let line = self.line_at(offset);
+                    if offset > 0 && line == self.line_at(offset - 1) {
                         write!(f, "   | ")?;
                     } else {
-                        write!(f, "{:4} ", lines[offset])?;
+                        write!(f, "{:4} ", line)?;
                     }
                     match opcode {
-                        Ret | Negate | Add | Subtract | Multiply | Divide => {
+                        Ret | Negate | Add | Subtract | Multiply | Divide | Nil | True | False
+                        | Not | Equal | Greater | Less | Print | Pop | CloseUpvalue | Inherit => {
                             writeln!(f, "{}", opcode)?;
                             1
                         }
                         Constant => {
+                            let lookup = bytecode[offset + 1] as usize;
+                            writeln!(f, "{:<16} {:4} '{}'", opcode, lookup, &literals[lookup])?;
+                            2
+                        }
+                        DefineGlobal | GetGlobal | SetGlobal | Class | GetProperty
+                        | SetProperty | Method | GetSuper => {
                             let lookup = bytecode[offset + 1] as usize;
                             writeln!(f, "{:<16} {:4} '{}'", opcode, lookup, &constants[lookup])?;
                             2
                         }
+                        ConstantLong => {
+                            let lookup = (bytecode[offset + 1] as usize) << 16
+                                | (bytecode[offset + 2] as usize) << 8
+                                | bytecode[offset + 3] as usize;
+                            writeln!(f, "{:<16} {:4} '{}'", opcode, lookup, &literals[lookup])?;
+                            4
+                        }
+                        Invoke | SuperInvoke => {
+                            let lookup = bytecode[offset + 1] as usize;
+                            let arg_count = bytecode[offset + 2];
+                            writeln!(
+                                f,
+                                "{:<16} ({} args) {:4} '{}'",
+                                opcode, arg_count, lookup, &constants[lookup]
+                            )?;
+                            3
+                        }
+                        Jump | JumpIfFalse => {
+                            let jump = (bytecode[offset + 1] as usize) << 8
+                                | bytecode[offset + 2] as usize;
+                            writeln!(f, "{:<16} {:4} -> {}", opcode, offset, offset + 3 + jump)?;
+                            3
+                        }
+                        Loop => {
+                            let jump = (bytecode[offset + 1] as usize) << 8
+                                | bytecode[offset + 2] as usize;
+                            writeln!(f, "{:<16} {:4} -> {}", opcode, offset, offset + 3 - jump)?;
+                            3
+                        }
+                        Closure => {
+                            let lookup = bytecode[offset + 1] as usize;
+                            let function = &constants[lookup];
+                            writeln!(f, "{:<16} {:4} {}", opcode, lookup, function)?;
+
+                            let upvalue_count = function.upvalue_count();
+                            for i in 0..upvalue_count {
+                                let operand = offset + 2 + 2 * i;
+                                let is_local = bytecode[operand] == 1;
+                                let index = bytecode[operand + 1];
+                                writeln!(
+                                    f,
+                                    "{:04}    |                     {} {}",
+                                    operand,
+                                    if is_local { "local" } else { "upvalue" },
+                                    index
+                                )?;
+                            }
+                            2 + 2 * upvalue_count
+                        }
+                        GetLocal | SetLocal | GetUpvalue | SetUpvalue | Call => {
+                            let slot = bytecode[offset + 1];
+                            writeln!(f, "{:<16} {:4}", opcode, slot)?;
+                            2
+                        }
                     }
                 }
                 Err(error) => {
@@ -90,6 +283,95 @@ impl<V: Display> Display for Chunk<V> {
             }
         }
 
-        Ok(())
+        // what encoding the line table saves over storing a line for every byte
+        let encoded = lines.len() * mem::size_of::<LineRun>();
+        let unencoded = bytecode.len() * mem::size_of::<usize>();
+        writeln!(
+            f,
+            "-- lines: {} runs in {} bytes, {} bytes saved over {} unencoded --",
+            lines.len(),
+            encoded,
+            unencoded.saturating_sub(encoded),
+            unencoded
+        )?;
+        writeln!(
+            f,
+            "-- spans: {} runs in {} bytes --",
+            spans.len(),
+            spans.len() * mem::size_of::<SpanRun>()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk() -> Chunk<f64> {
+        let source = Source {
+            name: "test".to_owned(),
+            code: "1\n2\n3\n".to_owned(),
+        };
+        Chunk::new("test".to_owned(), Rc::new(source))
+    }
+
+    fn span_on(line: usize) -> Span {
+        Span {
+            start: 2 * (line - 1),
+            end: 2 * (line - 1) + 1,
+            line,
+            column: 1,
+        }
+    }
+
+    #[test]
+    fn line_at_finds_the_run_of_every_byte() {
+        let mut chunk = chunk();
+        for (byte, line) in [1, 1, 1, 2, 3, 3].into_iter().enumerate() {
+            chunk.write_byte(byte as u8, span_on(line));
+        }
+        assert_eq!(chunk.lines.len(), 3);
+
+        // first and last byte of a run
+        assert_eq!(chunk.line_at(0), 1);
+        assert_eq!(chunk.line_at(2), 1);
+        // a run of a single byte, between two longer ones
+        assert_eq!(chunk.line_at(3), 2);
+        assert_eq!(chunk.line_at(4), 3);
+        assert_eq!(chunk.line_at(5), 3);
+    }
+
+    #[test]
+    fn span_at_locates_the_token_in_the_source() {
+        let mut chunk = chunk();
+        chunk.write_byte(0, span_on(1));
+        chunk.write_byte(0, span_on(3));
+        assert_eq!(chunk.span_at(0), span_on(1));
+        assert_eq!(chunk.span_at(1), span_on(3));
+    }
+
+    #[test]
+    fn write_constant_switches_to_long_operands_at_256() {
+        let mut chunk = chunk();
+        for i in 0..256 {
+            assert_eq!(chunk.write_constant(i as f64, span_on(1)), Some(i));
+        }
+        assert_eq!(chunk.bytecode_len(), 2 * 256);
+        let last_short = 2 * 255;
+        assert_eq!(chunk.get_byte(last_short), OpCode::Constant as u8);
+        assert_eq!(chunk.get_byte(last_short + 1), 255);
+
+        assert_eq!(chunk.write_constant(256.0, span_on(2)), Some(256));
+        let first_long = 2 * 256;
+        assert_eq!(chunk.get_byte(first_long), OpCode::ConstantLong as u8);
+        assert_eq!(
+            [1, 2, 3].map(|i| chunk.get_byte(first_long + i)),
+            [0x00, 0x01, 0x00]
+        );
+        assert_eq!(chunk.bytecode_len(), first_long + 4);
+        assert_eq!(*chunk.get_literal(256), 256.0);
+        // every byte of the instruction belongs to the line it was written on
+        assert_eq!(chunk.line_at(first_long - 1), 1);
+        assert_eq!(chunk.line_at(first_long + 3), 2);
     }
 }
@@ -1,6 +1,9 @@
 use std::fmt::{Debug, Display, Error, Formatter};
 
-#[derive(Debug, PartialEq)]
+use crate::chunk::Disassemble;
+use crate::object::{ObjRef, ObjString};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     LParen,
     RParen,
@@ -8,6 +11,8 @@ pub enum Token {
     RBrace,
     Comma,
     Eof,
+    // stands in for whatever the scanner failed to scan, once it has reported why
+    Error,
     Dot,
     Minus,
     Semicolon,
@@ -43,11 +48,118 @@ pub enum Token {
     KeywordTrue,
     KeywordVar,
     KeywordWhile,
+    KeywordBreak,
+    KeywordContinue,
     Identifier(String),
 }
 
-// TODO: add more constant types like string literals
-pub type Value = f64;
+// Where a token sits in the source. Start and end are byte offsets with the
+// end exclusive, line and column are those of its first character.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Values are small and Copy: anything bigger than a number lives on the heap
+// and is shared by reference
+#[derive(Debug, Clone, Copy)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    // nil and false are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match self {
+            Value::Obj(obj) => obj.as_string(),
+            _ => None,
+        }
+    }
+}
+
+impl Disassemble for Value {
+    fn upvalue_count(&self) -> usize {
+        match self {
+            Value::Obj(obj) => obj
+                .as_function()
+                .map_or(0, |function| function.upvalue_count),
+            _ => 0,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        use Value::*;
+        match (self, other) {
+            (Nil, Nil) => true,
+            (Bool(a), Bool(b)) => a == b,
+            // IEEE semantics, so NaN != NaN just like reference Lox
+            (Number(a), Number(b)) => a == b,
+            (Obj(a), Obj(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", format_number(*value)),
+            Value::Obj(obj) => write!(f, "{}", obj),
+        }
+    }
+}
+
+// Formats a number the way C's printf("%g") does, as reference Lox prints
+// them: six significant digits, switching to an exponent for very large or
+// small magnitudes, with trailing zeros dropped.
+fn format_number(value: f64) -> String {
+    const PRECISION: i32 = 6;
+
+    if value.is_nan() {
+        return "nan".to_owned();
+    }
+    if value.is_infinite() || value == 0.0 {
+        // already formatted as "inf", "-inf", "0" and "-0"
+        return format!("{}", value);
+    }
+
+    // the exponent picking the notation is the one after rounding
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, value);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("the e format always has an exponent");
+    let exponent: i32 = exponent.parse().expect("the exponent is an integer");
+
+    if (-4..PRECISION).contains(&exponent) {
+        let fixed = format!("{:.*}", (PRECISION - 1 - exponent) as usize, value);
+        trim_fraction(&fixed).to_owned()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    }
+}
+
+fn trim_fraction(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
 
 // Our instruction set
 
@@ -60,11 +172,43 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Invoke,
+    Inherit,
+    GetSuper,
+    SuperInvoke,
+    ConstantLong,
 }
 
 impl Display for OpCode {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "{:?}", self)
+        // pad so the disassembler can line up operands
+        f.pad(&format!("{:?}", self))
     }
 }
 
@@ -80,7 +224,64 @@ impl TryFrom<u8> for OpCode {
             4 => Ok(Subtract),
             5 => Ok(Multiply),
             6 => Ok(Divide),
+            7 => Ok(Nil),
+            8 => Ok(True),
+            9 => Ok(False),
+            10 => Ok(Not),
+            11 => Ok(Equal),
+            12 => Ok(Greater),
+            13 => Ok(Less),
+            14 => Ok(Print),
+            15 => Ok(Pop),
+            16 => Ok(DefineGlobal),
+            17 => Ok(GetGlobal),
+            18 => Ok(SetGlobal),
+            19 => Ok(GetLocal),
+            20 => Ok(SetLocal),
+            21 => Ok(Jump),
+            22 => Ok(JumpIfFalse),
+            23 => Ok(Loop),
+            24 => Ok(Call),
+            25 => Ok(Closure),
+            26 => Ok(GetUpvalue),
+            27 => Ok(SetUpvalue),
+            28 => Ok(CloseUpvalue),
+            29 => Ok(Class),
+            30 => Ok(GetProperty),
+            31 => Ok(SetProperty),
+            32 => Ok(Method),
+            33 => Ok(Invoke),
+            34 => Ok(Inherit),
+            35 => Ok(GetSuper),
+            36 => Ok(SuperInvoke),
+            37 => Ok(ConstantLong),
             _ => Err("Invalid opcode"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_format_like_printf_g() {
+        let cases = [
+            (1.0, "1"),
+            (-2.5, "-2.5"),
+            (0.1 + 0.2, "0.3"),
+            (123456.0, "123456"),
+            (1234567.0, "1.23457e+06"),
+            // rounding up can carry into the next power of ten
+            (999999.5, "1e+06"),
+            (1e22, "1e+22"),
+            (0.0001, "0.0001"),
+            (0.00001234, "1.234e-05"),
+            (-0.0, "-0"),
+            (f64::INFINITY, "inf"),
+        ];
+        for (number, expected) in cases {
+            assert_eq!(Value::Number(number).to_string(), expected);
+        }
+    }
+}
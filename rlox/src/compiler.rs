@@ -0,0 +1,1293 @@
+// single-pass pratt parser that compiles scanner tokens straight into bytecode
+
+use std::mem;
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::common::{OpCode, Span, Token, Value};
+use crate::diagnostic::{self, Source};
+use crate::list::List;
+use crate::object::{ObjFunction, ObjKind, ObjRef};
+use crate::scanner::Scanner;
+use crate::vm::{InterpretError, VM};
+
+// Lowest to highest binding power, the derived ordering follows declaration order
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        use Precedence::*;
+        match self {
+            None => Assignment,
+            Assignment => Or,
+            Or => And,
+            And => Equality,
+            Equality => Comparison,
+            Comparison => Term,
+            Term => Factor,
+            Factor => Unary,
+            Unary => Call,
+            Call | Primary => Primary,
+        }
+    }
+}
+
+// the flag tells a prefix rule whether it may consume a trailing '='
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+// Locals live in VM stack slots, so a local is just its name plus the scope it belongs to
+struct Local {
+    name: String,
+    // None while the initializer is still being compiled
+    depth: Option<usize>,
+    // captured locals are hoisted off the stack when they go out of scope
+    is_captured: bool,
+}
+
+// A variable captured from an enclosing function
+struct Upvalue {
+    // a slot of the directly enclosing function, or one of its own upvalues
+    index: u8,
+    is_local: bool,
+}
+
+// The innermost enclosing loop, which break and continue statements jump out of
+struct Loop {
+    // where continue jumps back to
+    start: usize,
+    // locals declared deeper than this belong to the loop body
+    scope_depth: usize,
+    // break jumps waiting to be patched to the loop's exit
+    break_jumps: List<usize>,
+}
+
+// A class declaration being compiled
+struct ClassState {
+    // subclasses keep their superclass in a local named "super"
+    has_superclass: bool,
+}
+
+// one byte slot operands cap how many locals and upvalues can be addressed
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
+const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// Everything tracked while compiling one function body. Function declarations
+// nest, so these stack up with the enclosing functions underneath.
+struct FunctionState {
+    // The function is allocated up front and its chunk is written in place
+    function: ObjRef,
+    kind: FunctionKind,
+
+    locals: List<Local>,
+    upvalues: List<Upvalue>,
+    scope_depth: usize,
+    loops: List<Loop>,
+}
+
+// one byte operands cap the parameter and argument counts too
+const ARGS_MAX: usize = u8::MAX as usize;
+
+struct Compiler<'a> {
+    scanner: Scanner,
+    vm: &'a mut VM,
+
+    current: Token,
+    current_span: Span,
+    previous: Token,
+    previous_span: Span,
+
+    had_error: bool,
+    // Set by the first error in a declaration. Until the next statement
+    // boundary, later errors are most likely fallout and go unreported.
+    panic_mode: bool,
+    functions: List<FunctionState>,
+    // enclosing class declarations, innermost last
+    classes: List<ClassState>,
+}
+
+// Compiles the whole script into a function taking no arguments. Every object
+// it refers to is allocated on the given VM's heap.
+pub fn compile(source: Rc<Source>, vm: &mut VM) -> Result<ObjRef, InterpretError> {
+    let mut compiler = Compiler::new(source, vm);
+    compiler.begin_function(FunctionKind::Script, None);
+
+    compiler.advance();
+    // errors are reported as they are found, compilation carries on past them
+    while !compiler.match_token(Token::Eof) {
+        compiler.declaration();
+    }
+
+    let (function, _) = compiler.end_function();
+
+    if compiler.had_error {
+        Err(InterpretError::Compile)
+    } else {
+        Ok(function)
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: Rc<Source>, vm: &'a mut VM) -> Self {
+        // nothing has been scanned yet
+        let start = Span {
+            start: 0,
+            end: 0,
+            line: 1,
+            column: 1,
+        };
+        Compiler {
+            scanner: Scanner::new(source),
+            vm,
+            current: Token::Eof,
+            current_span: start,
+            previous: Token::Eof,
+            previous_span: start,
+            had_error: false,
+            panic_mode: false,
+            functions: List::new(),
+            classes: List::new(),
+        }
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.functions
+            .last()
+            .expect("always compiling at least the script")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("always compiling at least the script")
+    }
+
+    fn current_function(&mut self) -> &mut ObjFunction {
+        let function = &mut self.state_mut().function;
+        match &mut function.kind {
+            ObjKind::Function(function) => function,
+            _ => unreachable!("only functions are ever compiled into"),
+        }
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk<Value> {
+        &mut self.current_function().chunk
+    }
+
+    fn begin_function(&mut self, kind: FunctionKind, name: Option<String>) {
+        let chunk_name = name.clone().unwrap_or_else(|| "script".to_owned());
+        let mut function = self.vm.alloc(ObjKind::Function(ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(chunk_name, self.scanner.source().clone()),
+            name: None,
+        }));
+        // rooted before interning the name, which may collect
+        self.vm.push_compiler_root(function);
+        if let Some(name) = name {
+            let name = self.vm.intern(name);
+            if let ObjKind::Function(function) = &mut function.kind {
+                function.name = Some(name);
+            }
+        }
+
+        let mut locals = List::new();
+        // Slot zero holds the function being called, or the receiver for
+        // methods. Naming it "this" is all it takes to make `this` resolve.
+        locals.push(Local {
+            name: match kind {
+                FunctionKind::Initializer | FunctionKind::Method => "this".to_owned(),
+                FunctionKind::Function | FunctionKind::Script => String::new(),
+            },
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        self.functions.push(FunctionState {
+            function,
+            kind,
+            locals,
+            upvalues: List::new(),
+            scope_depth: 0,
+            loops: List::new(),
+        });
+    }
+
+    // Returns the finished function along with the variables it captures
+    fn end_function(&mut self) -> (ObjRef, List<Upvalue>) {
+        self.emit_return();
+        let state = self
+            .functions
+            .pop()
+            .expect("begin_function was called first");
+        self.vm.pop_compiler_root();
+
+        #[cfg(feature = "print_code")]
+        if !self.had_error
+            && let ObjKind::Function(function) = &state.function.kind
+        {
+            println!("{}", function.chunk);
+        }
+
+        (state.function, state.upvalues)
+    }
+
+    // token plumbing
+
+    fn advance(&mut self) {
+        loop {
+            match self.scanner.emit_next() {
+                // comments carry no meaning past the scanner
+                Ok((Token::SlashSlash, _)) => continue,
+                Ok((token, span)) => {
+                    self.previous = mem::replace(&mut self.current, token);
+                    self.previous_span = mem::replace(&mut self.current_span, span);
+                    return;
+                }
+                // the parser gets an error token, which fails the declaration
+                // without being reported a second time
+                Err(err) => {
+                    err.report(self.scanner.source());
+                    self.had_error = true;
+                    self.previous = mem::replace(&mut self.current, Token::Error);
+                    self.previous_span = mem::replace(&mut self.current_span, err.span);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn check(&self, token: &Token) -> bool {
+        mem::discriminant(&self.current) == mem::discriminant(token)
+    }
+
+    fn match_token(&mut self, token: Token) -> bool {
+        if self.check(&token) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn consume(&mut self, token: Token, message: &str) {
+        if self.check(&token) {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    // Reports the error and enters panic mode, unless already in it
+    fn error_at(&mut self, token: &Token, span: Span, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.had_error = true;
+
+        let location = match token {
+            // the scanner has already said what is wrong there
+            Token::Error => return,
+            Token::Eof => " at end".to_owned(),
+            // quoted as written, rather than as the scanner parsed it
+            _ => format!(" at '{}'", self.scanner.lexeme(span)),
+        };
+        let message = format!("Error{}: {}", location, message);
+        eprintln!(
+            "{}",
+            diagnostic::render(self.scanner.source(), span, &message)
+        );
+    }
+
+    fn error(&mut self, message: &str) {
+        let token = self.previous.clone();
+        self.error_at(&token, self.previous_span, message);
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let token = self.current.clone();
+        self.error_at(&token, self.current_span, message);
+    }
+
+    // bytecode emission
+
+    fn emit_byte(&mut self, byte: u8) {
+        let span = self.previous_span;
+        self.current_chunk().write_byte(byte, span);
+    }
+
+    fn emit_op(&mut self, opcode: OpCode) {
+        self.emit_byte(opcode as u8);
+    }
+
+    // Emits with the span of an earlier token, so runtime errors point at an
+    // operator rather than at the end of its last operand
+    fn emit_op_at(&mut self, opcode: OpCode, span: Span) {
+        self.current_chunk().write_byte(opcode as u8, span);
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            // initializers always hand back the instance
+            self.emit_op(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            // falling off the end of a function returns nil
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Ret);
+    }
+
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let lookup = self.current_chunk().add_constant(value);
+        u8::try_from(lookup).unwrap_or_else(|_| {
+            self.error("Too many constants in one chunk.");
+            0
+        })
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let span = self.previous_span;
+        if self.current_chunk().write_constant(value, span).is_none() {
+            self.error("Too many literals in one chunk.");
+        }
+    }
+
+    // Emits a jump with a placeholder operand and returns where that operand
+    // lives, so it can be patched once the target is known
+    fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        self.emit_op(opcode);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().bytecode_len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to account for the jump operand itself
+        let jump = self.current_chunk().bytecode_len() - offset - 2;
+        let jump = u16::try_from(jump).unwrap_or_else(|_| {
+            self.error("Too much code to jump over.");
+            0
+        });
+
+        let [high, low] = jump.to_be_bytes();
+        self.current_chunk().set_byte(offset, high);
+        self.current_chunk().set_byte(offset + 1, low);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+
+        // +2 to also jump back over the loop operand itself
+        let offset = self.current_chunk().bytecode_len() - loop_start + 2;
+        let offset = u16::try_from(offset).unwrap_or_else(|_| {
+            self.error("Loop body too large.");
+            0
+        });
+
+        let [high, low] = offset.to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(low);
+    }
+
+    // The identifier just consumed. If consuming it failed, an empty name
+    // stands in so parsing can carry on in panic mode.
+    fn previous_identifier(&self) -> String {
+        match &self.previous {
+            Token::Identifier(name) => name.clone(),
+            _ => String::new(),
+        }
+    }
+
+    fn identifier_constant(&mut self, name: String) -> u8 {
+        let name = Value::Obj(self.vm.intern(name));
+        // Names are interned, so an earlier constant for the same name can be
+        // shared. This keeps one byte operands in reach in long scripts.
+        let existing = self
+            .current_chunk()
+            .constants()
+            .iter()
+            .position(|c| *c == name);
+        if let Some(lookup) = existing
+            && let Ok(lookup) = u8::try_from(lookup)
+        {
+            return lookup;
+        }
+        self.make_constant(name)
+    }
+
+    // scopes and variables
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        // the locals of the scope being left are the ones on top of the stack
+        while let Some(local) = self.state().locals.last() {
+            if local
+                .depth
+                .is_none_or(|depth| depth <= self.state().scope_depth)
+            {
+                break;
+            }
+
+            if local.is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.state().locals.len() == LOCALS_MAX {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        // globals are late bound, only locals are tracked at compile time
+        if self.state().scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous_identifier();
+        for local in self.state().locals.iter().rev() {
+            if local
+                .depth
+                .is_some_and(|depth| depth < self.state().scope_depth)
+            {
+                break;
+            }
+
+            if local.name == name {
+                self.error("Already a variable with this name in this scope.");
+                break;
+            }
+        }
+
+        self.add_local(name)
+    }
+
+    // Looks the name up among the locals of the function at `function_index`
+    // in the stack of functions being compiled
+    fn resolve_local(&mut self, function_index: usize, name: &str) -> Option<u8> {
+        // walk backwards so inner declarations shadow outer ones
+        let locals = &self.functions[function_index].locals;
+        let (slot, local) = locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        // add_local keeps the slot count within a byte
+        Some(slot as u8)
+    }
+
+    fn add_upvalue(&mut self, function_index: usize, index: u8, is_local: bool) -> u8 {
+        // a closure captures each variable once, however often it refers to it
+        let upvalues = &self.functions[function_index].upvalues;
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UPVALUES_MAX {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let state = &mut self.functions[function_index];
+        state.upvalues.push(Upvalue { index, is_local });
+        let count = state.upvalues.len();
+        if let ObjKind::Function(function) = &mut state.function.kind {
+            function.upvalue_count = count;
+        }
+
+        (count - 1) as u8
+    }
+
+    // Looks the name up in the functions enclosing the one at `function_index`,
+    // threading an upvalue through every function in between
+    fn resolve_upvalue(&mut self, function_index: usize, name: &str) -> Option<u8> {
+        if function_index == 0 {
+            // the script has nothing to capture from, it can only see globals
+            return None;
+        }
+        let enclosing = function_index - 1;
+
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.functions[enclosing].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(function_index, slot, true));
+        }
+
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(function_index, index, false))
+    }
+
+    fn parse_variable(&mut self, message: &str) -> u8 {
+        self.consume(Token::Identifier(String::new()), message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            // locals are addressed by slot, they need no name constant
+            return 0;
+        }
+
+        let name = self.previous_identifier();
+        self.identifier_constant(name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.state_mut().locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.state().scope_depth > 0 {
+            // the initializer's value already sits in the local's slot
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_op(OpCode::DefineGlobal);
+        self.emit_byte(global);
+    }
+
+    // declarations and statements
+
+    // Compiles a declaration. After an error in it, parsing carries on in
+    // panic mode to the end of the declaration, then picks up again at the
+    // next statement.
+    fn declaration(&mut self) {
+        self.declaration_or_statement();
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    // Skips tokens until one that likely starts a new statement, so a single
+    // mistake doesn't set off a cascade of errors
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(&Token::Eof) {
+            if self.previous == Token::Semicolon {
+                return;
+            }
+
+            match self.current {
+                Token::KeywordClass
+                | Token::KeywordFun
+                | Token::KeywordVar
+                | Token::KeywordFor
+                | Token::KeywordIf
+                | Token::KeywordWhile
+                | Token::KeywordPrint
+                | Token::KeywordReturn => return,
+                // left for the enclosing block to close itself with
+                Token::RBrace if self.state().scope_depth > 0 => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn declaration_or_statement(&mut self) {
+        if self.match_token(Token::KeywordClass) {
+            self.class_declaration()
+        } else if self.match_token(Token::KeywordFun) {
+            self.fun_declaration()
+        } else if self.match_token(Token::KeywordVar) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(Token::Identifier(String::new()), "Expect class name.");
+        let name_constant = self.identifier_constant(self.previous_identifier());
+        self.declare_variable();
+
+        let class_name = self.previous_identifier();
+        self.emit_op(OpCode::Class);
+        self.emit_byte(name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        self.class_body(class_name);
+        self.classes.pop();
+    }
+
+    fn class_body(&mut self, class_name: String) {
+        if self.match_token(Token::Less) {
+            self.consume(Token::Identifier(String::new()), "Expect superclass name.");
+            self.variable(false);
+            if self.previous_identifier() == class_name {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // The superclass stays on the stack as a local so methods can
+            // capture it for super calls. Each class needs its own scope for it.
+            self.begin_scope();
+            self.add_local("super".to_owned());
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_op(OpCode::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // methods are attached to the class sitting on top of the stack
+        self.named_variable(class_name, false);
+
+        self.consume(Token::LBrace, "Expect '{' before class body.");
+        while !self.check(&Token::RBrace) && !self.check(&Token::Eof) {
+            self.method();
+        }
+        self.consume(Token::RBrace, "Expect '}' after class body.");
+
+        self.emit_op(OpCode::Pop);
+        if self
+            .classes
+            .last()
+            .is_some_and(|class| class.has_superclass)
+        {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(Token::Identifier(String::new()), "Expect method name.");
+        let name = self.previous_identifier();
+        let kind = if name == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        let name_constant = self.identifier_constant(name);
+
+        self.function(kind);
+        self.emit_op(OpCode::Method);
+        self.emit_byte(name_constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // a function may refer to itself, so its name is usable straight away
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, kind: FunctionKind) {
+        self.begin_function(kind, Some(self.previous_identifier()));
+        // parameters are locals of the body's outermost scope, which is never
+        // ended since returning discards the whole frame anyway
+        self.begin_scope();
+
+        self.consume(Token::LParen, "Expect '(' after function name.");
+        if !self.check(&Token::RParen) {
+            loop {
+                let function = &mut self.state_mut().function;
+                let arity = match &mut function.kind {
+                    ObjKind::Function(function) => {
+                        function.arity += 1;
+                        function.arity
+                    }
+                    _ => unreachable!("only functions are ever compiled into"),
+                };
+                if arity > ARGS_MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let parameter = self.parse_variable("Expect parameter name.");
+                self.define_variable(parameter);
+
+                if !self.match_token(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RParen, "Expect ')' after parameters.");
+        self.consume(Token::LBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_function();
+        let function = self.make_constant(Value::Obj(function));
+        self.emit_op(OpCode::Closure);
+        self.emit_byte(function);
+
+        // one operand pair per captured variable tells the VM where to find it
+        for upvalue in upvalues.iter() {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(Token::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.consume(Token::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(Token::KeywordPrint) {
+            self.print_statement()
+        } else if self.match_token(Token::KeywordIf) {
+            self.if_statement()
+        } else if self.match_token(Token::KeywordReturn) {
+            self.return_statement()
+        } else if self.match_token(Token::KeywordWhile) {
+            self.while_statement()
+        } else if self.match_token(Token::KeywordFor) {
+            self.for_statement()
+        } else if self.match_token(Token::KeywordBreak) {
+            self.break_statement()
+        } else if self.match_token(Token::KeywordContinue) {
+            self.continue_statement()
+        } else if self.match_token(Token::LBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(&Token::RBrace) && !self.check(&Token::Eof) {
+            self.declaration();
+        }
+
+        self.consume(Token::RBrace, "Expect '}' after block.")
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(Token::LParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(Token::RParen, "Expect ')' after condition.");
+
+        // the condition stays on the stack for the jump, each branch pops it
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+
+        if self.match_token(Token::KeywordElse) {
+            self.statement();
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().kind == FunctionKind::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(Token::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(Token::Semicolon, "Expect ';' after return value.");
+            self.emit_op(OpCode::Ret);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().bytecode_len();
+        self.consume(Token::LParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(Token::RParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+        self.end_loop()
+    }
+
+    fn for_statement(&mut self) {
+        // a variable declared in the initializer is scoped to the loop
+        self.begin_scope();
+        self.consume(Token::LParen, "Expect '(' after 'for'.");
+        if self.match_token(Token::Semicolon) {
+            // no initializer
+        } else if self.match_token(Token::KeywordVar) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().bytecode_len();
+        let mut exit_jump = None;
+        if !self.match_token(Token::Semicolon) {
+            self.expression();
+            self.consume(Token::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop);
+        }
+
+        if !self.match_token(Token::RParen) {
+            // The increment is compiled before the body but runs after it, so
+            // the body jumps over it on the way in and loops back to it after
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().bytecode_len();
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(Token::RParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::Pop);
+        }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(Loop {
+            start,
+            scope_depth,
+            break_jumps: List::new(),
+        });
+    }
+
+    fn end_loop(&mut self) {
+        // breaks land after the condition has been popped, where the loop exits
+        let innermost = self
+            .state_mut()
+            .loops
+            .pop()
+            .expect("begin_loop was called first");
+        for break_jump in innermost.break_jumps {
+            self.patch_jump(break_jump);
+        }
+    }
+
+    // Jumping out of the body skips its end_scope, so pop its locals by hand.
+    // They stay declared since the rest of the body still refers to them.
+    fn discard_loop_locals(&mut self, loop_depth: usize) {
+        let discarded = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_some_and(|depth| depth > loop_depth))
+            .map(|local| local.is_captured)
+            .collect::<List<bool>>();
+
+        for is_captured in discarded {
+            if is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let loop_depth = match self.state().loops.last() {
+            Some(innermost) => innermost.scope_depth,
+            None => {
+                self.error("Can't use 'break' outside of a loop.");
+                return;
+            }
+        };
+        self.consume(Token::Semicolon, "Expect ';' after 'break'.");
+
+        self.discard_loop_locals(loop_depth);
+        let break_jump = self.emit_jump(OpCode::Jump);
+        if let Some(innermost) = self.state_mut().loops.last_mut() {
+            innermost.break_jumps.push(break_jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        let (loop_start, loop_depth) = match self.state().loops.last() {
+            Some(innermost) => (innermost.start, innermost.scope_depth),
+            None => {
+                self.error("Can't use 'continue' outside of a loop.");
+                return;
+            }
+        };
+        self.consume(Token::Semicolon, "Expect ';' after 'continue'.");
+
+        self.discard_loop_locals(loop_depth);
+        self.emit_loop(loop_start)
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(Token::Semicolon, "Expect ';' after value.");
+        self.emit_op(OpCode::Print);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(Token::Semicolon, "Expect ';' after expression.");
+        self.emit_op(OpCode::Pop);
+    }
+
+    // expressions
+
+    fn get_rule(token: &Token) -> ParseRule<'a> {
+        use Token::*;
+        let (prefix, infix, precedence): (Option<ParseFn<'a>>, Option<ParseFn<'a>>, Precedence) =
+            match token {
+                LParen => (Some(Self::grouping), Some(Self::call), Precedence::Call),
+                Minus => (Some(Self::unary), Some(Self::binary), Precedence::Term),
+                Plus => (None, Some(Self::binary), Precedence::Term),
+                Slash | Star => (None, Some(Self::binary), Precedence::Factor),
+                Bang => (Some(Self::unary), None, Precedence::None),
+                BangEqual | EqualEqual => (None, Some(Self::binary), Precedence::Equality),
+                Greater | GreaterEqual | Less | LessEqual => {
+                    (None, Some(Self::binary), Precedence::Comparison)
+                }
+                LiteralNumber(_) => (Some(Self::number), None, Precedence::None),
+                LiteralString(_) => (Some(Self::string), None, Precedence::None),
+                Identifier(_) => (Some(Self::variable), None, Precedence::None),
+                Dot => (None, Some(Self::dot), Precedence::Call),
+                KeywordThis => (Some(Self::this), None, Precedence::None),
+                KeywordSuper => (Some(Self::super_), None, Precedence::None),
+                KeywordAnd => (None, Some(Self::and), Precedence::And),
+                KeywordOr => (None, Some(Self::or), Precedence::Or),
+                KeywordFalse | KeywordNil | KeywordTrue => {
+                    (Some(Self::literal), None, Precedence::None)
+                }
+                _ => (None, None, Precedence::None),
+            };
+
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let prefix = match Self::get_rule(&self.previous).prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error("Expect expression.");
+                return;
+            }
+        };
+        // only a low enough precedence may treat a following '=' as assignment
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign);
+
+        while precedence <= Self::get_rule(&self.current).precedence {
+            self.advance();
+            // every token with a non-None precedence has an infix rule
+            let infix = Self::get_rule(&self.previous).infix.unwrap();
+            infix(self, can_assign);
+        }
+
+        if can_assign && self.match_token(Token::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        match self.previous {
+            Token::LiteralNumber(value) => self.emit_constant(Value::Number(value)),
+            _ => unreachable!("number rule is only registered for number literals"),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        let chars = match &self.previous {
+            Token::LiteralString(chars) => chars.clone(),
+            _ => unreachable!("string rule is only registered for string literals"),
+        };
+
+        let string = self.vm.intern(chars);
+        self.emit_constant(Value::Obj(string))
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous_identifier();
+        self.named_variable(name, can_assign)
+    }
+
+    fn named_variable(&mut self, name: String, can_assign: bool) {
+        let innermost = self.functions.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(innermost, &name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(index) = self.resolve_upvalue(innermost, &name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index)
+        } else {
+            let arg = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, arg)
+        };
+
+        if can_assign && self.match_token(Token::Equal) {
+            self.expression();
+            self.emit_op(set_op);
+        } else {
+            self.emit_op(get_op);
+        }
+        self.emit_byte(arg);
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        // a falsey left operand is the result, skip the right one entirely
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump)
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        // a truthy left operand is the result, jump over the right one
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump)
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is never assignable
+        self.named_variable("this".to_owned(), false)
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => {
+                self.error("Can't use 'super' outside of a class.");
+                return;
+            }
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.");
+                return;
+            }
+            Some(_) => {}
+        }
+
+        self.consume(Token::Dot, "Expect '.' after 'super'.");
+        self.consume(
+            Token::Identifier(String::new()),
+            "Expect superclass method name.",
+        );
+        let name = self.identifier_constant(self.previous_identifier());
+
+        // the receiver goes below the superclass, which the instruction pops
+        self.named_variable("this".to_owned(), false);
+        if self.match_token(Token::LParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super".to_owned(), false);
+            self.emit_op(OpCode::SuperInvoke);
+            self.emit_byte(name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable("super".to_owned(), false);
+            self.emit_op(OpCode::GetSuper);
+            self.emit_byte(name);
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous {
+            Token::KeywordFalse => self.emit_op(OpCode::False),
+            Token::KeywordNil => self.emit_op(OpCode::Nil),
+            Token::KeywordTrue => self.emit_op(OpCode::True),
+            _ => unreachable!("literal rule is only registered for literal keywords"),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(Token::RParen, "Expect ')' after expression.")
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_op(OpCode::Call);
+        self.emit_byte(arg_count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(
+            Token::Identifier(String::new()),
+            "Expect property name after '.'.",
+        );
+        let name = self.identifier_constant(self.previous_identifier());
+
+        if can_assign && self.match_token(Token::Equal) {
+            self.expression();
+            self.emit_op(OpCode::SetProperty);
+            self.emit_byte(name);
+        } else if self.match_token(Token::LParen) {
+            // calling straight away skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_op(OpCode::Invoke);
+            self.emit_byte(name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_op(OpCode::GetProperty);
+            self.emit_byte(name);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0;
+        if !self.check(&Token::RParen) {
+            loop {
+                self.expression();
+                if arg_count == ARGS_MAX {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RParen, "Expect ')' after arguments.");
+
+        // the check above keeps the count within a byte
+        arg_count as u8
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        let span = self.previous_span;
+
+        // compile the operand first, the operator applies to its result
+        self.parse_precedence(Precedence::Unary);
+
+        match operator {
+            Token::Minus => self.emit_op_at(OpCode::Negate, span),
+            Token::Bang => self.emit_op_at(OpCode::Not, span),
+            _ => unreachable!("unary rule is only registered for unary operators"),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        let span = self.previous_span;
+        let rule = Self::get_rule(&operator);
+
+        // left associative: the right operand binds one level tighter
+        self.parse_precedence(rule.precedence.next());
+
+        // a >= b is !(a < b) and so on, which keeps the instruction set small
+        let (opcode, negate) = match operator {
+            Token::Plus => (OpCode::Add, false),
+            Token::Minus => (OpCode::Subtract, false),
+            Token::Star => (OpCode::Multiply, false),
+            Token::Slash => (OpCode::Divide, false),
+            Token::BangEqual => (OpCode::Equal, true),
+            Token::EqualEqual => (OpCode::Equal, false),
+            Token::Greater => (OpCode::Greater, false),
+            Token::GreaterEqual => (OpCode::Less, true),
+            Token::Less => (OpCode::Less, false),
+            Token::LessEqual => (OpCode::Greater, true),
+            _ => unreachable!("binary rule is only registered for binary operators"),
+        };
+        self.emit_op_at(opcode, span);
+        if negate {
+            self.emit_op_at(OpCode::Not, span);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// renders errors against the source they came from, pointing at the exact span
+
+use std::fmt::Write;
+
+use crate::common::Span;
+
+// A named piece of lox code. Everything compiled from it shares it, so errors
+// found long after compiling can still quote the code.
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    pub code: String,
+}
+
+// Works out the line and column of the byte range, the way the scanner
+// would have counted them
+pub fn locate(source: &Source, start: usize, end: usize) -> Span {
+    let before = source.code.get(..start).unwrap_or("");
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    Span {
+        start,
+        end,
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    }
+}
+
+// Formats the message with the file, line and column it refers to, then the
+// offending source line with the span underlined:
+//
+//   script.lox:2:10: Error at ';': Expect expression.
+//      2 | print 1 +;
+//        |          ^
+pub fn render(source: &Source, span: Span, message: &str) -> String {
+    let mut out = format!(
+        "{}:{}:{}: {}\n",
+        source.name, span.line, span.column, message
+    );
+
+    let line = source.code.lines().nth(span.line - 1).unwrap_or("");
+    let gutter = span.line.to_string().len().max(4);
+    let _ = writeln!(out, "{:>gutter$} | {}", span.line, line);
+
+    // Tabs are kept so the underline lines up however wide they display
+    let indent: String = line
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // spans running onto later lines are underlined to the end of the first
+    let rest = line.chars().count().saturating_sub(span.column - 1);
+    let width = source
+        .code
+        .get(span.start..span.end)
+        .unwrap_or("")
+        .chars()
+        .count()
+        .min(rest)
+        .max(1);
+    let _ = write!(out, "{:gutter$} | {}^{}", "", indent, "~".repeat(width - 1));
+
+    out
+}
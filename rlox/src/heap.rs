@@ -0,0 +1,234 @@
+// owner of every object allocated while compiling and running lox code, and
+// the mark-sweep collector that frees the ones nothing can reach anymore
+
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+
+use crate::common::Value;
+use crate::list::List;
+use crate::object::{ObjKind, ObjRef, ObjString, ObjUpvalue};
+use crate::table::Table;
+
+// how much the heap may grow before the first collection
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+// the next collection waits until the surviving heap has grown by this factor
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+pub struct Heap {
+    objects: List<ObjRef>,
+    // Every string is interned, so two strings with the same characters are
+    // the same object and compare equal by identity alone. The table doesn't
+    // keep its strings alive, unreachable ones are dropped on collection.
+    strings: Table<InternedString, ()>,
+
+    bytes_allocated: usize,
+    next_gc: usize,
+    // marked objects whose references haven't been traced yet
+    gray: List<ObjRef>,
+}
+
+// The intern table's key. It is hashed and compared by its characters, so
+// the table can be searched by characters before any object holds them.
+struct InternedString(ObjRef);
+
+impl InternedString {
+    fn chars(&self) -> &str {
+        &self.0.as_string().expect("only strings are interned").chars
+    }
+}
+
+impl Hash for InternedString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chars().hash(state);
+    }
+}
+
+impl PartialEq for InternedString {
+    fn eq(&self, other: &Self) -> bool {
+        self.chars() == other.chars()
+    }
+}
+
+impl Eq for InternedString {}
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        self.chars()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: List::new(),
+            strings: Table::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            gray: List::new(),
+        }
+    }
+
+    pub fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        let obj = ObjRef::new(kind);
+        self.bytes_allocated += obj.size();
+        self.objects.push(obj);
+
+        #[cfg(feature = "log_gc")]
+        eprintln!("{:p} allocate {} for {}", obj, obj.size(), obj.type_name());
+
+        obj
+    }
+
+    pub fn intern(&mut self, chars: String) -> ObjRef {
+        if let Some(interned) = self.strings.get_key(chars.as_str()) {
+            return interned.0;
+        }
+
+        let string = self.alloc(ObjKind::String(ObjString { chars }));
+        self.strings.insert(InternedString(string), ());
+        string
+    }
+
+    // Whether enough has been allocated since the last collection to run another
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "stress_gc") || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, mut obj: ObjRef) {
+        if obj.is_marked {
+            return;
+        }
+        obj.is_marked = true;
+        self.gray.push(obj);
+
+        #[cfg(feature = "log_gc")]
+        eprintln!("{:p} mark {} of {}", obj, obj.size(), obj.type_name());
+    }
+
+    // Frees everything not reachable from the objects marked so far. The
+    // owner of the roots marks them first, the heap takes it from there.
+    pub fn collect(&mut self) {
+        #[cfg(feature = "log_gc")]
+        let before = self.bytes_allocated;
+
+        self.trace_references();
+
+        // interned strings about to be freed must not be handed out again
+        self.strings.retain(|string, _| string.0.is_marked);
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated.max(1) * GC_HEAP_GROW_FACTOR;
+
+        #[cfg(feature = "log_gc")]
+        eprintln!(
+            "-- gc end\n   collected {} bytes (from {} to {}) next at {}",
+            before.saturating_sub(self.bytes_allocated),
+            before,
+            self.bytes_allocated,
+            self.next_gc
+        );
+    }
+
+    // The roots are marked before the heap gets to do its part, so the owner
+    // of the roots opens the cycle's log
+    #[cfg(feature = "log_gc")]
+    pub fn log_begin(&self) {
+        eprintln!("-- gc begin");
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            self.blacken(obj);
+        }
+    }
+
+    // marks everything the object refers to
+    fn blacken(&mut self, obj: ObjRef) {
+        #[cfg(feature = "log_gc")]
+        eprintln!("{:p} blacken {} of {}", obj, obj.size(), obj.type_name());
+
+        match &obj.kind {
+            ObjKind::String(_) => {}
+            ObjKind::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for &constant in function.chunk.values() {
+                    self.mark_value(constant);
+                }
+            }
+            ObjKind::Closure(closure) => {
+                self.mark_object(closure.function);
+                for &upvalue in closure.upvalues.iter() {
+                    self.mark_object(upvalue);
+                }
+            }
+            // open upvalues point into the stack, which is a root anyway
+            ObjKind::Upvalue(ObjUpvalue::Open(_)) => {}
+            ObjKind::Upvalue(ObjUpvalue::Closed(value)) => self.mark_value(*value),
+            ObjKind::Class(class) => {
+                self.mark_object(class.name);
+                for (&name, &method) in class.methods.iter() {
+                    self.mark_object(name);
+                    self.mark_value(method);
+                }
+            }
+            ObjKind::Instance(instance) => {
+                self.mark_object(instance.class);
+                for (&name, &value) in instance.fields.iter() {
+                    self.mark_object(name);
+                    self.mark_value(value);
+                }
+            }
+            ObjKind::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            ObjKind::Native(native) => self.mark_object(native.name),
+        }
+    }
+
+    fn sweep(&mut self) {
+        // sizes are recounted from the survivors since objects like functions
+        // keep growing after they are allocated
+        self.bytes_allocated = 0;
+
+        let mut live = List::new();
+        while let Some(mut obj) = self.objects.pop() {
+            if obj.is_marked {
+                obj.is_marked = false;
+                self.bytes_allocated += obj.size();
+                live.push(obj);
+            } else {
+                Self::free(obj);
+            }
+        }
+        self.objects = live;
+
+        #[cfg(feature = "log_gc")]
+        eprintln!("-- gc sweep kept {} objects", self.objects.len());
+    }
+
+    fn free(obj: ObjRef) {
+        #[cfg(feature = "log_gc")]
+        eprintln!("{:p} free {} of {}", obj, obj.size(), obj.type_name());
+
+        // only the heap hands out handles, and only reachable ones survive a sweep
+        unsafe { obj.free() };
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        // nothing can refer to the objects once their owner is gone
+        while let Some(obj) = self.objects.pop() {
+            Self::free(obj);
+        }
+    }
+}
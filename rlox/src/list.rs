@@ -4,6 +4,7 @@ use std::iter::{DoubleEndedIterator, FromIterator, IntoIterator, Iterator};
 use std::mem;
 use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut, Drop};
+use std::ptr;
 use std::ptr::NonNull;
 use std::slice;
 
@@ -99,6 +100,37 @@ impl<T> List<T> {
             })
         }
     }
+
+    pub fn insert(&mut self, index: usize, elem: T) {
+        assert!(index <= self.len, "index out of bounds");
+        if self.len == self.cap {
+            self.grow();
+        }
+
+        unsafe {
+            // shift everything from index onwards one to the right,
+            // `copy` handles the overlapping ranges like memmove
+            ptr::copy(
+                self.arr.add(index).as_ptr(),
+                self.arr.add(index + 1).as_ptr(),
+                self.len - index,
+            );
+            self.arr.add(index).write(elem);
+        }
+
+        self.len += 1;
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        // popping drops each element in turn, the allocation is kept for reuse
+        while self.len > len {
+            self.pop();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
 }
 
 pub struct ListIter<T> {
@@ -188,7 +220,7 @@ impl<T> Drop for ListIter<T> {
 
 impl<T> Drop for List<T> {
     fn drop(&mut self) {
-        while self.pop().is_some() {}
+        self.clear();
 
         // NonNull::dangling() yields a pointer, albeit invalid.
         // This avoids freeing a dangling pointer.
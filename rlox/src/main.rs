@@ -4,35 +4,31 @@ use std::io::{self, Write};
 use std::process;
 
 mod vm;
-use crate::vm::VM;
+use crate::vm::{InterpretError, VM};
 
 mod scanner;
-use crate::scanner::Scanner;
+
+mod compiler;
 
 mod chunk;
-use crate::chunk::Chunk;
 
 mod list;
 
+mod table;
+
 mod common;
-use crate::common::Token;
+
+mod object;
+
+mod heap;
 
 mod util;
 
-fn interpret(code: String) {
-    let mut scanner = Scanner::new(code);
+mod diagnostic;
 
-    loop {
-        match scanner.emit_next() {
-            Ok(token) => {
-                println!("{:#?}", token);
-                if token == Token::Eof {
-                    break;
-                }
-            }
-            Err(err) => err.report(),
-        }
-    }
+fn interpret(vm: &mut VM, name: &str, code: String) -> Result<(), InterpretError> {
+    let script = vm.compile(name, code)?;
+    vm.interpret(script)
 }
 
 fn run_file(path: &String) {
@@ -41,10 +37,17 @@ fn run_file(path: &String) {
         String::new()
     });
 
-    interpret(code);
+    let mut vm = VM::new();
+    if let Err(err) = interpret(&mut vm, path, code) {
+        process::exit(match err {
+            InterpretError::Compile => 65,
+            InterpretError::Runtime => 70,
+        });
+    }
 }
 
 fn run_repl() {
+    let mut vm = VM::new();
     loop {
         print!("> ");
         let _ = io::stdout().flush();
@@ -59,7 +62,8 @@ fn run_repl() {
             break;
         }
 
-        interpret(line);
+        // errors are reported as they happen, the session carries on
+        let _ = interpret(&mut vm, "repl", line);
     }
 }
 
@@ -86,50 +90,6 @@ fn rlox_main() {
     }
 }
 
-fn debug_main() {
-    use crate::common::OpCode::*;
-    let mut chunk = Chunk::new("my first bytecode!".to_owned());
-
-    // for i in 1..=10 {
-    //     chunk.write_byte(Constant as u8, 123);
-    //     let const_lookup = chunk.add_constant(Value::try_from(i).expect("should be ok")) as u8;
-    //     chunk.write_byte(const_lookup, 123);
-    // }
-
-    // chunk.write_byte(Negate as u8, 123);
-
-    {
-        // testing add operation
-        let one = chunk.add_constant(2.2);
-        chunk.write_byte(Constant as u8, 123);
-        chunk.write_byte(one as u8, 123);
-
-        let mut two = chunk.add_constant(3.4);
-        chunk.write_byte(Constant as u8, 123);
-        chunk.write_byte(two as u8, 123);
-
-        chunk.write_byte(Add as u8, 123);
-
-        two = chunk.add_constant(5.6);
-        chunk.write_byte(Constant as u8, 123);
-        chunk.write_byte(two as u8, 123);
-
-        chunk.write_byte(Divide as u8, 123);
-
-        chunk.write_byte(Negate as u8, 123);
-    }
-
-    chunk.write_byte(Ret as u8, 123);
-    println!("{}", &chunk);
-
-    let mut vm = VM::new();
-    match vm.interpret(&mut chunk) {
-        Ok(_) => {}
-        Err(err) => println!("{:?}", err),
-    }
-}
-
 fn main() {
-    // debug_main();
     rlox_main();
 }
@@ -0,0 +1,228 @@
+// heap object representations that values refer to
+
+use std::fmt::{self, Display, Error, Formatter};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+use crate::chunk::Chunk;
+use crate::common::Value;
+use crate::list::List;
+use crate::table::Table;
+
+pub struct Obj {
+    // set while the collector finds the object reachable, cleared by the sweep
+    pub is_marked: bool,
+    pub kind: ObjKind,
+}
+
+pub enum ObjKind {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+// Strings are immutable once allocated, which is what makes interning them safe
+pub struct ObjString {
+    pub chars: String,
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk<Value>,
+    // None for the top level script
+    pub name: Option<ObjRef>,
+}
+
+// A function paired with the variables it captured when it was created, every
+// function runs wrapped in one of these
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: List<ObjRef>,
+}
+
+// A captured variable. It refers to its stack slot for as long as that slot is
+// alive, then takes the value over so closures can outlive the frame.
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    // closures keyed by interned method name
+    pub methods: Table<ObjRef, Value>,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    // fields are added on first assignment, keyed by interned name
+    pub fields: Table<ObjRef, Value>,
+}
+
+// A method read off an instance, remembering the instance it was read from
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// Host code a native runs with its arguments. An Err carries the message of a
+// runtime error to raise in the script.
+pub type NativeFn = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+// A function implemented in Rust and registered with the VM
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+// A copyable handle to a heap object. Handles compare by identity, the object
+// behind them is only valid for as long as whoever allocated it keeps it alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    // Moves the object onto the heap. The caller owns the allocation and must
+    // eventually hand it back to `free`.
+    pub fn new(kind: ObjKind) -> Self {
+        let obj = Box::new(Obj {
+            is_marked: false,
+            kind,
+        });
+        ObjRef(NonNull::from(Box::leak(obj)))
+    }
+
+    // Safety: the handle must come from `new`, must not have been freed already
+    // and no copies of it may be used afterwards.
+    pub unsafe fn free(self) {
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+    }
+}
+
+impl Obj {
+    #[cfg(feature = "log_gc")]
+    pub fn type_name(&self) -> &'static str {
+        match &self.kind {
+            ObjKind::String(_) => "string",
+            ObjKind::Function(_) => "function",
+            ObjKind::Closure(_) => "closure",
+            ObjKind::Upvalue(_) => "upvalue",
+            ObjKind::Class(_) => "class",
+            ObjKind::Instance(_) => "instance",
+            ObjKind::BoundMethod(_) => "bound method",
+            ObjKind::Native(_) => "native",
+        }
+    }
+
+    // Roughly how many bytes the object keeps alive, counting what it owns
+    // outside of the object itself
+    pub fn size(&self) -> usize {
+        let table = |len: usize| len * mem::size_of::<(ObjRef, Value)>();
+        mem::size_of::<Obj>()
+            + match &self.kind {
+                ObjKind::String(string) => string.chars.capacity(),
+                ObjKind::Function(function) => function.chunk.size(),
+                ObjKind::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+                ObjKind::Class(class) => table(class.methods.capacity()),
+                ObjKind::Instance(instance) => table(instance.fields.capacity()),
+                ObjKind::Upvalue(_) | ObjKind::BoundMethod(_) | ObjKind::Native(_) => 0,
+            }
+    }
+
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match &self.kind {
+            ObjKind::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&ObjFunction> {
+        match &self.kind {
+            ObjKind::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&ObjClosure> {
+        match &self.kind {
+            ObjKind::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&ObjClass> {
+        match &self.kind {
+            ObjKind::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&ObjInstance> {
+        match &self.kind {
+            ObjKind::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+}
+
+impl Deref for ObjRef {
+    type Target = Obj;
+    fn deref(&self) -> &Self::Target {
+        // handles are only ever created from live allocations
+        unsafe { self.0.as_ref() }
+    }
+}
+
+// Handles are freely copied, so it is up to the caller not to hold two mutable
+// borrows of the same object at once
+impl DerefMut for ObjRef {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl Display for Obj {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match &self.kind {
+            ObjKind::String(string) => write!(f, "{}", string.chars),
+            ObjKind::Function(function) => write!(f, "{}", function),
+            ObjKind::Closure(closure) => write!(f, "{}", closure.function),
+            ObjKind::Upvalue(_) => write!(f, "upvalue"),
+            ObjKind::Class(class) => write!(f, "{}", class.name),
+            ObjKind::Instance(instance) => match &instance.class.kind {
+                ObjKind::Class(class) => write!(f, "{} instance", class.name),
+                _ => unreachable!("instances are only made from classes"),
+            },
+            ObjKind::BoundMethod(bound) => write!(f, "{}", bound.method),
+            ObjKind::Native(native) => write!(f, "<native fn {}>", native.name),
+        }
+    }
+}
+
+impl Display for ObjFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl fmt::Pointer for ObjRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        fmt::Pointer::fmt(&self.0, f)
+    }
+}
+
+impl Display for ObjRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}", **self)
+    }
+}
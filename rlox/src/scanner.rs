@@ -1,52 +1,95 @@
 // handwritten scanner/lexer for the lox syntax grammar
 
+use std::rc::Rc;
+
 use crate::list::List;
 
-use crate::common::Token;
+use crate::common::{Span, Token};
+use crate::diagnostic::{self, Source};
 
 #[derive(Debug)]
 pub struct Scanner {
-    source: String,
+    source: Rc<Source>,
 
     line: usize,
+    // offset of the first character on the current line
+    line_start_pos: usize,
+    // offset of the first character of the token being scanned
     lex_start_pos: usize,
     lex_curr_pos: usize,
+    // line and column the token being scanned starts at
+    lex_start_line: usize,
+    lex_start_column: usize,
 }
 
 pub struct ScanError {
-    line: usize,
+    pub span: Span,
     message: String,
 }
 
 impl ScanError {
-    pub fn report(&self) {
-        eprintln!("[line {}] Error: {}", self.line, self.message);
+    pub fn report(&self, source: &Source) {
+        let message = format!("Error: {}", self.message);
+        eprintln!("{}", diagnostic::render(source, self.span, &message));
     }
 }
 
 impl Scanner {
-    pub fn new(code: String) -> Self {
+    pub fn new(source: Rc<Source>) -> Self {
         Scanner {
-            source: code,
+            source,
             line: 1,
-            lex_curr_pos: 0,
+            line_start_pos: 0,
             lex_start_pos: 0,
+            lex_curr_pos: 0,
+            lex_start_line: 1,
+            lex_start_column: 1,
+        }
+    }
+
+    pub fn source(&self) -> &Rc<Source> {
+        &self.source
+    }
+
+    // The source text a span covers
+    pub fn lexeme(&self, span: Span) -> &str {
+        &self.source.code[span.start..span.end]
+    }
+
+    // spans everything scanned since the current token began
+    fn span(&self) -> Span {
+        Span {
+            start: self.lex_start_pos,
+            end: self.lex_curr_pos,
+            line: self.lex_start_line,
+            column: self.lex_start_column,
         }
     }
 
+    // Column of an offset on the current line, counting characters rather
+    // than bytes
+    fn column_at(&self, pos: usize) -> usize {
+        self.source.code[self.line_start_pos..pos].chars().count() + 1
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start_pos = self.lex_curr_pos;
+    }
+
     fn can_scan(&self) -> bool {
-        self.lex_curr_pos < self.source.len()
+        self.lex_curr_pos < self.source.code.len()
     }
 
     fn peek_next(&self) -> u8 {
         assert!(self.can_scan());
-        self.source.as_bytes()[self.lex_curr_pos]
+        self.source.code.as_bytes()[self.lex_curr_pos]
     }
 
     fn scan_next(&mut self) -> u8 {
         assert!(self.can_scan());
 
-        let c = self.source.as_bytes()[self.lex_curr_pos];
+        let c = self.source.code.as_bytes()[self.lex_curr_pos];
         self.lex_curr_pos += 1;
         c
     }
@@ -77,7 +120,7 @@ impl Scanner {
                     self.scan_next();
 
                     if c == b'\n' {
-                        self.line += 1;
+                        self.new_line();
                     }
 
                     self.can_scan()
@@ -87,9 +130,66 @@ impl Scanner {
         }
     }
 
-    pub fn emit_next(&mut self) -> Result<Token, ScanError> {
-        use Token::*;
+    // Scans what follows a backslash in a string literal into the character
+    // it stands for. A bad escape is consumed up to where it went wrong, but
+    // never past the end of its line.
+    fn scan_escape(&mut self) -> Result<char, String> {
+        let c = match self.source.code[self.lex_curr_pos..].chars().next() {
+            Some(c) if c != '\n' => c,
+            _ => return Err("Invalid escape sequence '\\'".to_owned()),
+        };
+        self.lex_curr_pos += c.len_utf8();
+
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            'u' => self.scan_unicode_escape(),
+            _ => Err(format!("Invalid escape sequence '\\{}'", c)),
+        }
+    }
+
+    // \u{XXXX} with one to six hex digits naming a Unicode scalar value
+    fn scan_unicode_escape(&mut self) -> Result<char, String> {
+        let malformed = || "Unicode escapes must look like '\\u{XXXX}'".to_owned();
+
+        if !self.can_scan() || self.peek_next() != b'{' {
+            return Err(malformed());
+        }
+        self.scan_next();
+
+        let digits_pos = self.lex_curr_pos;
+        while self.can_scan() && self.peek_next().is_ascii_hexdigit() {
+            self.scan_next();
+        }
+        let digits = self.source.code[digits_pos..self.lex_curr_pos].to_owned();
+        if digits.is_empty() || digits.len() > 6 || !self.can_scan() || self.peek_next() != b'}' {
+            return Err(malformed());
+        }
+        self.scan_next();
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("Invalid code point in '\\u{{{}}}'", digits))
+    }
+
+    // Scans the next token along with where it was found. Eof spans nothing
+    // at the very end of the source.
+    pub fn emit_next(&mut self) -> Result<(Token, Span), ScanError> {
         self.skip_whitespace();
+
+        self.lex_start_pos = self.lex_curr_pos;
+        self.lex_start_line = self.line;
+        self.lex_start_column = self.column_at(self.lex_start_pos);
+
+        let token = self.scan_token()?;
+        Ok((token, self.span()))
+    }
+
+    fn scan_token(&mut self) -> Result<Token, ScanError> {
+        use Token::*;
         if !self.can_scan() {
             return Ok(Eof);
         }
@@ -128,27 +228,56 @@ impl Scanner {
             // TODO: multiline does not work in REPL mode
             b'"' => {
                 let mut literal = List::<u8>::new();
+                let mut terminated = false;
+                // the rest of the string is still scanned past a bad escape, so
+                // scanning resumes after the closing quote
+                let mut bad_escape = None;
                 while self.can_scan() {
+                    let escape_pos = self.lex_curr_pos;
                     let nc = self.scan_next();
-                    literal.push(nc);
-
-                    if nc == b'\n' {
-                        self.line += 1;
-                    } else if nc == b'"' {
-                        break;
+                    match nc {
+                        b'"' => {
+                            terminated = true;
+                            break;
+                        }
+                        b'\\' => match self.scan_escape() {
+                            Ok(escaped) => {
+                                let mut utf8 = [0; 4];
+                                for &byte in escaped.encode_utf8(&mut utf8).as_bytes() {
+                                    literal.push(byte);
+                                }
+                            }
+                            Err(message) => {
+                                bad_escape.get_or_insert(ScanError {
+                                    span: Span {
+                                        start: escape_pos,
+                                        end: self.lex_curr_pos,
+                                        line: self.line,
+                                        column: self.column_at(escape_pos),
+                                    },
+                                    message,
+                                });
+                            }
+                        },
+                        b'\n' => {
+                            literal.push(nc);
+                            self.new_line();
+                        }
+                        _ => literal.push(nc),
                     }
                 }
 
-                if literal.is_empty() || *literal.last().unwrap() != b'"' {
+                if !terminated {
                     Err(ScanError {
-                        line: self.line,
+                        span: self.span(),
                         message: format!(
                             "Unterminated string literal \"{}",
                             str::from_utf8(&literal as &[u8]).unwrap()
                         ),
                     })
+                } else if let Some(err) = bad_escape {
+                    Err(err)
                 } else {
-                    let _ = literal.pop();
                     Ok(LiteralString(
                         str::from_utf8(&literal as &[u8]).unwrap().to_owned(),
                     ))
@@ -210,13 +339,23 @@ impl Scanner {
                     "true" => KeywordTrue,
                     "var" => KeywordVar,
                     "while" => KeywordWhile,
+                    "break" => KeywordBreak,
+                    "continue" => KeywordContinue,
                     _ => Identifier(raw_identifier),
                 }
             }),
-            _ => Err(ScanError {
-                line: self.line,
-                message: format!("Unexpected character '{}'", char::from(c)),
-            }),
+            _ => {
+                // skip the whole character so spans stay on character boundaries
+                let c = self.source.code[self.lex_start_pos..]
+                    .chars()
+                    .next()
+                    .expect("a byte was just scanned here");
+                self.lex_curr_pos = self.lex_start_pos + c.len_utf8();
+                Err(ScanError {
+                    span: self.span(),
+                    message: format!("Unexpected character '{}'", c),
+                })
+            }
         }
     }
 }
@@ -0,0 +1,367 @@
+use std::alloc::{Layout, alloc, dealloc, handle_alloc_error};
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+use std::ptr::NonNull;
+use std::slice;
+
+// A handwritten open addressing hash map in the same spirit as List, minus
+// Send/Sync traits equipped
+// https://craftinginterpreters.com/hash-tables.html
+
+// rehash once this fraction of the buckets are full or tombstones
+const MAX_LOAD_NUMERATOR: usize = 3;
+const MAX_LOAD_DENOMINATOR: usize = 4;
+
+const MIN_CAPACITY: usize = 8;
+
+// Enough buckets for the live entries to take up at most half the max load,
+// so a rehash leaves room for as many insertions again
+fn capacity_for(live: usize) -> usize {
+    let mut cap = MIN_CAPACITY;
+    while live * 2 * MAX_LOAD_DENOMINATOR > cap * MAX_LOAD_NUMERATOR {
+        cap *= 2;
+    }
+    cap
+}
+
+enum Bucket<K, V> {
+    Empty,
+    // Left behind by removed entries so probe sequences running through the
+    // bucket carry on past it. Lookups skip them, inserts reuse them.
+    Tombstone,
+    Full(K, V),
+}
+
+pub struct Table<K, V> {
+    buckets: NonNull<Bucket<K, V>>,
+    // always zero or a power of two, so a hash is wrapped with a mask
+    cap: usize,
+    // full buckets plus tombstones, both of which lengthen probe sequences
+    count: usize,
+}
+
+// FNV-1a, which is quick on the short keys lox code uses and doesn't need
+// the DoS resistance of the standard library's default hasher
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn hash<K: Hash + ?Sized>(key: &K) -> usize {
+    let mut hasher = FnvHasher(0xcbf29ce484222325);
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+impl<K: Hash + Eq, V> Table<K, V> {
+    pub fn new() -> Self {
+        Table {
+            buckets: NonNull::dangling(),
+            cap: 0,
+            count: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    fn buckets(&self) -> &[Bucket<K, V>] {
+        // as with List, as_ptr keeps the provenance of the whole allocation
+        unsafe { slice::from_raw_parts(self.buckets.as_ptr().cast_const(), self.cap) }
+    }
+
+    fn buckets_mut(&mut self) -> &mut [Bucket<K, V>] {
+        unsafe { slice::from_raw_parts_mut(self.buckets.as_ptr(), self.cap) }
+    }
+
+    // Index of the bucket holding the key, or failing that the bucket it
+    // should go in. Only called once there is at least one bucket. Like the
+    // standard library's maps, any borrowed form of the key will do.
+    fn find_bucket<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        let buckets = self.buckets();
+        let mask = self.cap - 1;
+        let mut index = hash(key) & mask;
+        let mut tombstone = None;
+
+        // The load factor guarantees an empty bucket, so the probe ends
+        loop {
+            match &buckets[index] {
+                Bucket::Empty => return tombstone.unwrap_or(index),
+                Bucket::Tombstone => {
+                    tombstone.get_or_insert(index);
+                }
+                Bucket::Full(k, _) if k.borrow() == key => return index,
+                Bucket::Full(..) => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    fn resize(&mut self, new_cap: usize) {
+        let new_layout =
+            Layout::array::<Bucket<K, V>>(new_cap).expect("capacity always fits in 1..isize::MAX");
+
+        let new_buckets = unsafe { alloc(new_layout) } as *mut Bucket<K, V>;
+        let new_buckets = match NonNull::new(new_buckets) {
+            Some(new_buckets) => new_buckets,
+            None => handle_alloc_error(new_layout),
+        };
+        for i in 0..new_cap {
+            unsafe { new_buckets.add(i).write(Bucket::Empty) };
+        }
+
+        let old_buckets = self.buckets;
+        let old_cap = self.cap;
+        self.buckets = new_buckets;
+        self.cap = new_cap;
+        // tombstones are left behind, so only the entries are counted again
+        self.count = 0;
+
+        for i in 0..old_cap {
+            // each old bucket is moved out exactly once before the old
+            // allocation is freed
+            if let Bucket::Full(key, value) = unsafe { old_buckets.add(i).read() } {
+                let index = self.find_bucket(&key);
+                self.buckets_mut()[index] = Bucket::Full(key, value);
+                self.count += 1;
+            }
+        }
+
+        if old_cap > 0 {
+            let old_layout = Layout::array::<Bucket<K, V>>(old_cap).unwrap();
+            unsafe { dealloc(old_buckets.as_ptr() as *mut u8, old_layout) };
+        }
+    }
+
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        if self.cap == 0 {
+            return None;
+        }
+
+        match &self.buckets()[self.find_bucket(key)] {
+            Bucket::Full(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        if self.cap == 0 {
+            return None;
+        }
+
+        let index = self.find_bucket(key);
+        match &mut self.buckets_mut()[index] {
+            Bucket::Full(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // The stored key equal to the given one, for tables used as sets
+    pub fn get_key<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<&K>
+    where
+        K: Borrow<Q>,
+    {
+        if self.cap == 0 {
+            return None;
+        }
+
+        match &self.buckets()[self.find_bucket(key)] {
+            Bucket::Full(key, _) => Some(key),
+            _ => None,
+        }
+    }
+
+    // Returns the value the key was previously mapped to, if any
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if (self.count + 1) * MAX_LOAD_DENOMINATOR > self.cap * MAX_LOAD_NUMERATOR {
+            // Rehashing drops the tombstones. When they make up most of the
+            // count the live entries fit at the current capacity.
+            let live = self.iter().count();
+            self.resize(capacity_for(live).max(self.cap));
+        }
+
+        let index = self.find_bucket(&key);
+        let bucket = &mut self.buckets_mut()[index];
+        // a reused tombstone was already counted
+        let is_empty = matches!(bucket, Bucket::Empty);
+        let old = std::mem::replace(bucket, Bucket::Full(key, value));
+        if is_empty {
+            self.count += 1;
+        }
+
+        match old {
+            Bucket::Full(_, old) => Some(old),
+            _ => None,
+        }
+    }
+
+    // Removes every entry the predicate rejects
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut keep: F) {
+        for bucket in self.buckets_mut() {
+            if let Bucket::Full(key, value) = bucket
+                && !keep(key, value)
+            {
+                *bucket = Bucket::Tombstone;
+            }
+        }
+    }
+
+    // Copies every entry of another table into this one, overwriting entries
+    // with the same keys
+    pub fn add_all(&mut self, from: &Table<K, V>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        for (key, value) in from.iter() {
+            self.insert(key.clone(), value.clone());
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets().iter().filter_map(|bucket| match bucket {
+            Bucket::Full(key, value) => Some((key, value)),
+            _ => None,
+        })
+    }
+}
+
+impl<K, V> Drop for Table<K, V> {
+    fn drop(&mut self) {
+        // a table nothing was inserted into never allocated its buckets
+        if self.cap == 0 {
+            return;
+        }
+
+        unsafe {
+            // drop the keys and values still held before freeing the buckets
+            for i in 0..self.cap {
+                self.buckets.add(i).drop_in_place();
+            }
+
+            let layout = Layout::array::<Bucket<K, V>>(self.cap).unwrap();
+            dealloc(self.buckets.as_ptr() as *mut u8, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_reuses_tombstone_without_counting_it_again() {
+        let mut table = Table::new();
+        table.insert(1, "one");
+        table.insert(2, "two");
+        table.retain(|&key, _| key != 1);
+        let count = table.count;
+
+        // 1 hashes to the bucket its tombstone sits in
+        assert_eq!(table.insert(1, "uno"), None);
+        assert_eq!(table.count, count);
+        assert_eq!(table.get(&1), Some(&"uno"));
+        assert!(
+            !table
+                .buckets()
+                .iter()
+                .any(|bucket| matches!(bucket, Bucket::Tombstone))
+        );
+    }
+
+    #[test]
+    fn lookups_probe_past_tombstones() {
+        let mut table = Table::new();
+        for key in 0..4 {
+            table.insert(key, key * 10);
+        }
+        // small enough that the collider doesn't make the table grow
+        let mask = table.capacity() - 1;
+        // a key sharing another's first bucket has to probe past it
+        let first = hash(&0) & mask;
+        let collider = (4..)
+            .find(|key| hash(key) & mask == first)
+            .expect("some key lands in the same bucket");
+        table.insert(collider, 42);
+        assert_eq!(table.capacity(), mask + 1);
+
+        table.retain(|&key, _| key != 0);
+        assert!(matches!(table.buckets()[first], Bucket::Tombstone));
+        assert_eq!(table.get(&collider), Some(&42));
+        assert_eq!(table.get(&0), None);
+    }
+
+    #[test]
+    fn grow_rehashes_every_entry() {
+        let mut table = Table::new();
+        table.insert(0, 0);
+        let initial = table.capacity();
+
+        for key in 1..100 {
+            table.insert(key, key * 2);
+        }
+        assert!(table.capacity() > initial);
+        for key in 0..100 {
+            assert_eq!(table.get(&key), Some(&(key * 2)));
+        }
+        assert_eq!(table.iter().count(), 100);
+    }
+
+    #[test]
+    fn rehash_drops_tombstones() {
+        let mut table = Table::new();
+        for key in 0..6 {
+            table.insert(key, ());
+        }
+        table.retain(|&key, _| key % 2 == 0);
+        assert_eq!(table.count, 6);
+
+        // the tombstones count towards the load factor until the next rehash,
+        // which finds room for the live entries without growing
+        table.insert(6, ());
+        assert_eq!(table.capacity(), 8);
+        assert_eq!(table.count, 4);
+    }
+
+    #[test]
+    fn tombstones_do_not_make_the_table_grow() {
+        let mut table = Table::new();
+        for key in 0..100_000 {
+            table.insert(key, ());
+            // like the intern table, which is swept on every collection
+            if key % 1000 == 999 {
+                table.retain(|_, _| false);
+            }
+        }
+        assert!(table.capacity() <= 4096);
+    }
+
+    #[test]
+    fn insert_overwrites_and_returns_old_value() {
+        let mut table = Table::new();
+        assert_eq!(table.insert("key".to_owned(), 1), None);
+        assert_eq!(table.insert("key".to_owned(), 2), Some(1));
+        assert_eq!(table.get(&"key".to_owned()), Some(&2));
+        assert_eq!(table.iter().count(), 1);
+    }
+}
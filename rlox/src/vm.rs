@@ -1,12 +1,55 @@
 use crate::chunk::Chunk;
+use crate::compiler;
+use crate::diagnostic::{self, Source};
+use crate::heap::Heap;
+use crate::object::{
+    ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative, ObjRef,
+    ObjUpvalue,
+};
 use std::fmt::{Display, Error, Formatter};
+use std::rc::Rc;
+use std::time::Instant;
 
 use crate::list::List;
+use crate::table::Table;
 
 use crate::common::{OpCode, Value};
 
+// how deep calls may nest before the VM reports a stack overflow
+const FRAMES_MAX: usize = 64;
+
+// A function invocation in progress
+#[derive(Clone, Copy)]
+struct CallFrame {
+    closure: ObjRef,
+    // offset of the next instruction to execute in the function's chunk
+    ip: usize,
+    // where the frame's window into the value stack begins, slot zero holds the callee
+    slots: usize,
+}
+
+impl CallFrame {
+    fn function(&self) -> &ObjFunction {
+        let closure = self.closure.as_closure().expect("frames only run closures");
+        closure
+            .function
+            .as_function()
+            .expect("closures only wrap functions")
+    }
+}
+
 pub struct VM {
-    stack: List<usize>,
+    stack: List<Value>,
+    frames: List<CallFrame>,
+    // upvalues still pointing into the stack, sorted by slot
+    open_upvalues: List<ObjRef>,
+    heap: Heap,
+    // keyed by interned names, so hashing the handle is as good as hashing the name
+    globals: Table<ObjRef, Value>,
+    // interned once so looking up initializers doesn't intern on every call
+    init_string: ObjRef,
+    // functions the compiler is still writing, which nothing else refers to yet
+    compiler_roots: List<ObjRef>,
 }
 
 #[derive(Debug)]
@@ -17,88 +60,742 @@ pub enum InterpretError {
 
 impl VM {
     pub fn new() -> Self {
-        VM { stack: List::new() }
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init".to_owned());
+        let mut vm = VM {
+            stack: List::new(),
+            frames: List::new(),
+            open_upvalues: List::new(),
+            heap,
+            globals: Table::new(),
+            init_string,
+            compiler_roots: List::new(),
+        };
+
+        // seconds since the VM started, good enough for timing scripts
+        let start = Instant::now();
+        vm.define_native("clock", 0, move |_| {
+            Ok(Value::Number(start.elapsed().as_secs_f64()))
+        });
+
+        vm
     }
 
-    fn interpret_binary_op(
-        &mut self,
-        chunk: &mut Chunk<Value>,
-        ip: usize,
-        binop: fn(Value, Value) -> Value,
-    ) -> Result<(), ()> {
-        if self.stack.len() < 2 {
-            Err(())
+    // Exposes a Rust function to scripts as a global. The VM checks the
+    // argument count against `arity` before the function is called, returning
+    // Err from it raises a runtime error with that message.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        // the name lives on the stack while the native is allocated
+        let name = self.intern(name.to_owned());
+        self.push(Value::Obj(name));
+        let native = self.alloc(ObjKind::Native(ObjNative {
+            name,
+            arity,
+            function: Box::new(function),
+        }));
+        self.globals.insert(name, Value::Obj(native));
+        self.stack.pop();
+    }
+
+    // Allocates on the VM's heap, collecting garbage first when it's due. Any
+    // object the new one refers to must already be reachable from a root.
+    pub fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(kind)
+    }
+
+    pub fn intern(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(chars)
+    }
+
+    // The compiler registers each function it starts so collections triggered
+    // while compiling keep it, and pops it again once the function is finished
+    pub fn push_compiler_root(&mut self, function: ObjRef) {
+        self.compiler_roots.push(function);
+    }
+
+    pub fn pop_compiler_root(&mut self) {
+        self.compiler_roots.pop();
+    }
+
+    fn collect_garbage(&mut self) {
+        #[cfg(feature = "log_gc")]
+        self.heap.log_begin();
+
+        for &value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in self.globals.iter() {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        for &function in self.compiler_roots.iter() {
+            self.heap.mark_object(function);
+        }
+        self.heap.mark_object(self.init_string);
+
+        self.heap.collect();
+    }
+
+    // Compiled functions refer to objects on this VM's heap, so they can only be
+    // run by the VM that compiled them. The name is what errors call the code.
+    pub fn compile(&mut self, name: &str, code: String) -> Result<ObjRef, InterpretError> {
+        let source = Rc::new(Source {
+            name: name.to_owned(),
+            code,
+        });
+        let result = compiler::compile(source, self);
+        // a failed compile bails out without finishing its functions
+        self.compiler_roots.clear();
+        result
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Result<Value, InterpretError> {
+        self.stack.pop().ok_or(InterpretError::Runtime)
+    }
+
+    fn peek(&self, distance: usize) -> Result<Value, InterpretError> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(i) => Ok(self.stack[i]),
+            None => Err(InterpretError::Runtime),
+        }
+    }
+
+    fn read_object(chunk: &Chunk<Value>, ip: usize) -> ObjRef {
+        match chunk.get_constant(chunk.get_byte(ip + 1) as usize) {
+            Value::Obj(name) => *name,
+            _ => unreachable!("the compiler only emits object operands here"),
+        }
+    }
+
+    fn read_short(chunk: &Chunk<Value>, ip: usize) -> usize {
+        (chunk.get_byte(ip + 1) as usize) << 8 | chunk.get_byte(ip + 2) as usize
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("only called while running")
+    }
+
+    fn runtime_error(&self, message: &str) -> InterpretError {
+        // point at the instruction that failed, then show how it was reached
+        if let Some(frame) = self.frames.last() {
+            let chunk = &frame.function().chunk;
+            let message = format!("Runtime error: {}", message);
+            let rendered = diagnostic::render(chunk.source(), chunk.span_at(frame.ip), &message);
+            eprintln!("{}", rendered);
         } else {
-            let two_idx = self.stack.pop().unwrap();
-            let one_idx = self.stack.pop().unwrap();
+            eprintln!("Runtime error: {}", message);
+        }
+
+        // innermost call first
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let function = frame.function();
+            // callers have already stepped past their call instruction
+            let offset = if depth == 0 { frame.ip } else { frame.ip - 1 };
+            let line = function.chunk.line_at(offset);
+            match function.name {
+                Some(name) => eprintln!("[line {}] in {}()", line, name),
+                None => eprintln!("[line {}] in script", line),
+            }
+        }
+
+        InterpretError::Runtime
+    }
 
-            let two = *chunk.get_constant(two_idx);
-            let one = *chunk.get_constant(one_idx);
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let function = closure
+            .as_closure()
+            .expect("only called with closures")
+            .function;
+        let arity = function
+            .as_function()
+            .expect("closures only wrap functions")
+            .arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
 
-            let res_idx = chunk.add_constant(binop(one, two));
-            self.stack.push(res_idx);
-            Ok(())
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
         }
+
+        // the callee and its arguments become the bottom of the new frame
+        let slots = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots,
+        });
+        Ok(())
     }
 
-    pub fn interpret(&mut self, chunk: &mut Chunk<Value>) -> Result<(), InterpretError> {
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        if let Value::Obj(obj) = callee {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            match &obj.kind {
+                ObjKind::Closure(_) => return self.call(obj, arg_count),
+                ObjKind::Native(native) => {
+                    if arg_count != native.arity {
+                        let message =
+                            format!("Expected {} arguments but got {}.", native.arity, arg_count);
+                        return Err(self.runtime_error(&message));
+                    }
+
+                    // natives run straight away without a frame of their own
+                    let args = &self.stack[callee_slot + 1..];
+                    let result =
+                        (native.function)(args).map_err(|message| self.runtime_error(&message))?;
+                    self.stack.truncate(callee_slot);
+                    self.push(result);
+                    return Ok(());
+                }
+                ObjKind::BoundMethod(bound) => {
+                    // the receiver becomes `this` in slot zero of the method's frame
+                    self.stack[callee_slot] = bound.receiver;
+                    return self.call(bound.method, arg_count);
+                }
+                ObjKind::Class(class) => {
+                    let initializer = class.methods.get(&self.init_string).copied();
+                    // the new instance takes the class's place under the arguments
+                    let instance = self.alloc(ObjKind::Instance(ObjInstance {
+                        class: obj,
+                        fields: Table::new(),
+                    }));
+                    self.stack[callee_slot] = Value::Obj(instance);
+
+                    return match initializer {
+                        Some(Value::Obj(init)) => self.call(init, arg_count),
+                        _ if arg_count != 0 => {
+                            let message = format!("Expected 0 arguments but got {}.", arg_count);
+                            Err(self.runtime_error(&message))
+                        }
+                        _ => Ok(()),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let receiver = self.peek(arg_count)?;
+        let instance = match receiver {
+            Value::Obj(obj) if matches!(obj.kind, ObjKind::Instance(_)) => obj,
+            _ => return Err(self.runtime_error("Only instances have methods.")),
+        };
+        let instance = instance
+            .as_instance()
+            .expect("checked to be an instance above");
+
+        // a field holding something callable shadows a method of the same name
+        if let Some(&field) = instance.fields.get(&name) {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.stack[callee_slot] = field;
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        let class = class
+            .as_class()
+            .expect("instances are only made from classes");
+        match class.methods.get(&name) {
+            Some(Value::Obj(method)) => self.call(*method, arg_count),
+            _ => {
+                let message = format!("Undefined property '{}'.", name);
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    // Replaces the instance on top of the stack with its method bound to it
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), InterpretError> {
+        let class = class
+            .as_class()
+            .expect("instances are only made from classes");
+        let method = match class.methods.get(&name) {
+            Some(Value::Obj(method)) => *method,
+            _ => {
+                let message = format!("Undefined property '{}'.", name);
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        // the receiver stays on the stack until the bound method is allocated
+        let receiver = self.peek(0)?;
+        let bound = self.alloc(ObjKind::BoundMethod(ObjBoundMethod { receiver, method }));
+        self.pop()?;
+        self.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        // closures over the same variable must share one upvalue
+        let mut index = self.open_upvalues.len();
+        while index > 0 {
+            let upvalue = self.open_upvalues[index - 1];
+            match upvalue.kind {
+                ObjKind::Upvalue(ObjUpvalue::Open(open)) if open == slot => return upvalue,
+                ObjKind::Upvalue(ObjUpvalue::Open(open)) if open < slot => break,
+                _ => index -= 1,
+            }
+        }
+
+        let upvalue = self.alloc(ObjKind::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(index, upvalue);
+        upvalue
+    }
+
+    // Moves every variable living at or above `last` off the stack and into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let mut upvalue = upvalue;
+            match upvalue.kind {
+                ObjKind::Upvalue(ObjUpvalue::Open(slot)) if slot >= last => {
+                    upvalue.kind = ObjKind::Upvalue(ObjUpvalue::Closed(self.stack[slot]));
+                    self.open_upvalues.pop();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn interpret_binary_op(&mut self, binop: fn(f64, f64) -> Value) -> Result<(), InterpretError> {
+        match (self.peek(1)?, self.peek(0)?) {
+            (Value::Number(one), Value::Number(two)) => {
+                // operands were pushed left to right so they come off in reverse
+                self.pop()?;
+                self.pop()?;
+
+                self.push(binop(one, two));
+                Ok(())
+            }
+            _ => Err(self.runtime_error("Operands must be numbers.")),
+        }
+    }
+
+    fn concatenate(&mut self) -> Result<(), InterpretError> {
+        let chars = match (self.peek(1)?.as_string(), self.peek(0)?.as_string()) {
+            (Some(one), Some(two)) => format!("{}{}", one.chars, two.chars),
+            _ => return Err(InterpretError::Runtime),
+        };
+
+        self.pop()?;
+        self.pop()?;
+
+        let string = self.intern(chars);
+        self.push(Value::Obj(string));
+        Ok(())
+    }
+
+    // Chunks are only ever read, so the same compiled script can be run any number of times
+    pub fn interpret(&mut self, script: ObjRef) -> Result<(), InterpretError> {
+        // the function sits in the closure's slot until the closure exists
+        self.push(Value::Obj(script));
+        let script = self.alloc(ObjKind::Closure(ObjClosure {
+            function: script,
+            upvalues: List::new(),
+        }));
+        self.stack[0] = Value::Obj(script);
+        let result = self.call(script, 0).and_then(|_| self.run());
+
+        // A failed run can leave operands, frames and open upvalues behind.
+        // Closures that escaped still need the values their upvalues point at.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        result
+    }
+
+    fn run(&mut self) -> Result<(), InterpretError> {
         use OpCode::*;
-        let mut ip = 0;
 
         loop {
+            let frame = *self
+                .frames
+                .last()
+                .expect("a frame is pushed before running");
+            let CallFrame {
+                closure, ip, slots, ..
+            } = frame;
+            let chunk = &frame.function().chunk;
+
             let step = match OpCode::try_from(chunk.get_byte(ip)) {
                 Err(_) => return Err(InterpretError::Runtime),
                 Ok(Ret) => {
-                    if let Some(chunk_idx) = self.stack.pop() {
-                        println!("{}", chunk.get_constant(chunk_idx));
+                    let result = self.pop()?;
+                    // the frame's locals are about to go, anything captured must move out
+                    self.close_upvalues(slots);
+                    self.frames.pop();
+
+                    if self.frames.is_empty() {
+                        // only the script itself is left on the stack
+                        self.pop()?;
+                        return Ok(());
                     }
-                    return Ok(());
+
+                    // discard the callee, its arguments and its locals in one go
+                    self.stack.truncate(slots);
+                    self.push(result);
+                    continue;
+                }
+                Ok(Call) => {
+                    let arg_count = chunk.get_byte(ip + 1) as usize;
+                    let caller = self.frames.len() - 1;
+                    self.call_value(self.peek(arg_count)?, arg_count)?;
+
+                    // The caller resumes after the call once the callee returns. Not
+                    // every callee pushes a frame, so the caller is found by index.
+                    self.frames[caller].ip += 2;
+                    continue;
+                }
+                Ok(SuperInvoke) => {
+                    let name = Self::read_object(chunk, ip);
+                    let arg_count = chunk.get_byte(ip + 2) as usize;
+                    let caller = self.frames.len() - 1;
+                    let superclass = self.pop()?;
+                    let Value::Obj(superclass) = superclass else {
+                        unreachable!("super always refers to a class");
+                    };
+                    self.invoke_from_class(superclass, name, arg_count)?;
+
+                    self.frames[caller].ip += 3;
+                    continue;
+                }
+                Ok(Invoke) => {
+                    let name = Self::read_object(chunk, ip);
+                    let arg_count = chunk.get_byte(ip + 2) as usize;
+                    let caller = self.frames.len() - 1;
+                    self.invoke(name, arg_count)?;
+
+                    self.frames[caller].ip += 3;
+                    continue;
                 }
                 Ok(Constant) => {
                     // TODO: this can potentially be out of bounds
                     let lookup = chunk.get_byte(ip + 1);
-                    let value = chunk.get_constant(lookup as usize);
-                    println!("{}", &value);
-                    self.stack.push(lookup as usize);
+                    self.push(*chunk.get_literal(lookup as usize));
                     2
                 }
+                Ok(ConstantLong) => {
+                    let lookup = (chunk.get_byte(ip + 1) as usize) << 16
+                        | (chunk.get_byte(ip + 2) as usize) << 8
+                        | chunk.get_byte(ip + 3) as usize;
+                    self.push(*chunk.get_literal(lookup));
+                    4
+                }
                 Ok(Negate) => {
-                    match self.stack.last() {
-                        None => return Err(InterpretError::Runtime),
-                        Some(chunk_idx) => {
-                            let constant = *chunk.get_constant(*chunk_idx);
-                            chunk.update_constant(*chunk_idx, -constant);
+                    match self.peek(0)? {
+                        Value::Number(value) => {
+                            self.pop()?;
+                            self.push(Value::Number(-value));
+                        }
+                        _ => {
+                            return Err(self.runtime_error("Operand must be a number."));
                         }
                     }
                     1
                 }
                 Ok(Add) => {
-                    if let Err(_) = self.interpret_binary_op(chunk, ip, |v1, v2| v1 + v2) {
-                        return Err(InterpretError::Runtime);
+                    match (self.peek(1)?, self.peek(0)?) {
+                        (Value::Number(_), Value::Number(_)) => {
+                            self.interpret_binary_op(|v1, v2| Value::Number(v1 + v2))?
+                        }
+                        (one, two) if one.as_string().is_some() && two.as_string().is_some() => {
+                            self.concatenate()?
+                        }
+                        _ => {
+                            return Err(
+                                self.runtime_error("Operands must be two numbers or two strings.")
+                            );
+                        }
                     }
                     1
                 }
                 Ok(Subtract) => {
-                    if let Err(_) = self.interpret_binary_op(chunk, ip, |v1, v2| v1 - v2) {
-                        return Err(InterpretError::Runtime);
-                    }
+                    self.interpret_binary_op(|v1, v2| Value::Number(v1 - v2))?;
                     1
                 }
                 Ok(Multiply) => {
-                    if let Err(_) = self.interpret_binary_op(chunk, ip, |v1, v2| v1 * v2) {
-                        return Err(InterpretError::Runtime);
-                    }
+                    self.interpret_binary_op(|v1, v2| Value::Number(v1 * v2))?;
                     1
                 }
                 Ok(Divide) => {
-                    if let Err(_) = self.interpret_binary_op(chunk, ip, |v1, v2| v1 / v2) {
-                        return Err(InterpretError::Runtime);
+                    self.interpret_binary_op(|v1, v2| Value::Number(v1 / v2))?;
+                    1
+                }
+                Ok(Nil) => {
+                    self.push(Value::Nil);
+                    1
+                }
+                Ok(True) => {
+                    self.push(Value::Bool(true));
+                    1
+                }
+                Ok(False) => {
+                    self.push(Value::Bool(false));
+                    1
+                }
+                Ok(Not) => {
+                    let value = self.pop()?;
+                    self.push(Value::Bool(value.is_falsey()));
+                    1
+                }
+                Ok(Equal) => {
+                    let two = self.pop()?;
+                    let one = self.pop()?;
+                    self.push(Value::Bool(one == two));
+                    1
+                }
+                Ok(Greater) => {
+                    self.interpret_binary_op(|v1, v2| Value::Bool(v1 > v2))?;
+                    1
+                }
+                Ok(Less) => {
+                    self.interpret_binary_op(|v1, v2| Value::Bool(v1 < v2))?;
+                    1
+                }
+                Ok(Print) => {
+                    println!("{}", self.pop()?);
+                    1
+                }
+                Ok(Pop) => {
+                    self.pop()?;
+                    1
+                }
+                Ok(DefineGlobal) => {
+                    let name = Self::read_object(chunk, ip);
+                    // redefinition is allowed at the top level, it just overwrites
+                    self.globals.insert(name, self.peek(0)?);
+                    self.pop()?;
+                    2
+                }
+                Ok(GetGlobal) => {
+                    let name = Self::read_object(chunk, ip);
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push(value),
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name);
+                            return Err(self.runtime_error(&message));
+                        }
+                    }
+                    2
+                }
+                Ok(GetLocal) => {
+                    let slot = chunk.get_byte(ip + 1) as usize;
+                    self.push(self.stack[slots + slot]);
+                    2
+                }
+                Ok(SetLocal) => {
+                    let slot = chunk.get_byte(ip + 1) as usize;
+                    // assignment is an expression, so the value stays on the stack
+                    self.stack[slots + slot] = self.peek(0)?;
+                    2
+                }
+                Ok(Closure) => {
+                    let function = Self::read_object(chunk, ip);
+                    let upvalue_count = function
+                        .as_function()
+                        .expect("closures only wrap functions")
+                        .upvalue_count;
+
+                    let mut upvalues = List::new();
+                    for i in 0..upvalue_count {
+                        let is_local = chunk.get_byte(ip + 2 + 2 * i) == 1;
+                        let index = chunk.get_byte(ip + 3 + 2 * i) as usize;
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            // the enclosing function already captured it
+                            closure
+                                .as_closure()
+                                .expect("frames only run closures")
+                                .upvalues[index]
+                        });
+                    }
+
+                    let closure = self.alloc(ObjKind::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Obj(closure));
+                    2 + 2 * upvalue_count
+                }
+                Ok(GetUpvalue) => {
+                    let index = chunk.get_byte(ip + 1) as usize;
+                    let upvalue = closure
+                        .as_closure()
+                        .expect("frames only run closures")
+                        .upvalues[index];
+                    let value = match upvalue.kind {
+                        ObjKind::Upvalue(ObjUpvalue::Open(slot)) => self.stack[slot],
+                        ObjKind::Upvalue(ObjUpvalue::Closed(value)) => value,
+                        _ => unreachable!("closures only capture upvalues"),
+                    };
+                    self.push(value);
+                    2
+                }
+                Ok(SetUpvalue) => {
+                    let index = chunk.get_byte(ip + 1) as usize;
+                    let mut upvalue = closure
+                        .as_closure()
+                        .expect("frames only run closures")
+                        .upvalues[index];
+                    // assignment is an expression, so the value stays on the stack
+                    let value = self.peek(0)?;
+                    match &mut upvalue.kind {
+                        ObjKind::Upvalue(ObjUpvalue::Open(slot)) => self.stack[*slot] = value,
+                        ObjKind::Upvalue(ObjUpvalue::Closed(closed)) => *closed = value,
+                        _ => unreachable!("closures only capture upvalues"),
+                    }
+                    2
+                }
+                Ok(CloseUpvalue) => {
+                    // the variable on top of the stack is going out of scope
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
+                    1
+                }
+                Ok(Class) => {
+                    let name = Self::read_object(chunk, ip);
+                    let class = self.alloc(ObjKind::Class(ObjClass {
+                        name,
+                        methods: Table::new(),
+                    }));
+                    self.push(Value::Obj(class));
+                    2
+                }
+                Ok(Method) => {
+                    let name = Self::read_object(chunk, ip);
+                    let method = self.peek(0)?;
+                    // the class declaration keeps the class just below its methods
+                    if let Value::Obj(mut class) = self.peek(1)?
+                        && let ObjKind::Class(class) = &mut class.kind
+                    {
+                        class.methods.insert(name, method);
+                    }
+                    self.pop()?;
+                    2
+                }
+                Ok(Inherit) => {
+                    let superclass = match self.peek(1)? {
+                        Value::Obj(obj) if matches!(obj.kind, ObjKind::Class(_)) => obj,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    // Methods are copied down before the subclass declares its own,
+                    // so overrides simply replace them and lookups never walk a chain
+                    let superclass = superclass.as_class().expect("checked to be a class above");
+                    if let Value::Obj(mut subclass) = self.peek(0)?
+                        && let ObjKind::Class(subclass) = &mut subclass.kind
+                    {
+                        subclass.methods.add_all(&superclass.methods);
                     }
+                    self.pop()?;
                     1
                 }
+                Ok(GetSuper) => {
+                    let name = Self::read_object(chunk, ip);
+                    let Value::Obj(superclass) = self.pop()? else {
+                        unreachable!("super always refers to a class");
+                    };
+                    // the receiver is now on top, ready to be bound
+                    self.bind_method(superclass, name)?;
+                    2
+                }
+                Ok(GetProperty) => {
+                    let instance = match self.peek(0)? {
+                        Value::Obj(obj) if matches!(obj.kind, ObjKind::Instance(_)) => obj,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let name = Self::read_object(chunk, ip);
+
+                    let field = match &instance.kind {
+                        ObjKind::Instance(instance) => instance.fields.get(&name).copied(),
+                        _ => unreachable!("checked to be an instance above"),
+                    };
+                    match field {
+                        Some(value) => {
+                            self.pop()?;
+                            self.push(value);
+                        }
+                        None => {
+                            let class = instance.as_instance().expect("checked above").class;
+                            self.bind_method(class, name)?;
+                        }
+                    }
+                    2
+                }
+                Ok(SetProperty) => {
+                    let mut instance = match self.peek(1)? {
+                        Value::Obj(obj) if matches!(obj.kind, ObjKind::Instance(_)) => obj,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let name = Self::read_object(chunk, ip);
+
+                    let value = self.pop()?;
+                    if let ObjKind::Instance(instance) = &mut instance.kind {
+                        instance.fields.insert(name, value);
+                    }
+
+                    // assignment is an expression, leave the value where the instance was
+                    self.pop()?;
+                    self.push(value);
+                    2
+                }
+                Ok(Jump) => 3 + Self::read_short(chunk, ip),
+                Ok(Loop) => {
+                    self.frame_mut().ip = ip + 3 - Self::read_short(chunk, ip);
+                    continue;
+                }
+                Ok(JumpIfFalse) => {
+                    // the condition is left for the compiled code to pop
+                    if self.peek(0)?.is_falsey() {
+                        3 + Self::read_short(chunk, ip)
+                    } else {
+                        3
+                    }
+                }
+                Ok(SetGlobal) => {
+                    let name = Self::read_object(chunk, ip);
+                    let value = self.peek(0)?;
+                    match self.globals.get_mut(&name) {
+                        // assignment is an expression, so the value stays on the stack
+                        Some(global) => *global = value,
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name);
+                            return Err(self.runtime_error(&message));
+                        }
+                    }
+                    2
+                }
             };
 
-            ip += step;
+            self.frame_mut().ip += step;
         }
     }
 }